@@ -0,0 +1,17 @@
+#!/bin/sh
+# Symbolizes the backtraces in a kernel log, e.g. the serial output of `cargo test`:
+#
+#   scripts/symbolize.sh target/x86_64-blog_os/debug/blog_os serial.log
+#
+# The kernel is loaded at the addresses it was linked at, so addr2line can look them up directly.
+# Reads the log from stdin if no file is given.
+set -e
+
+if [ $# -lt 1 ]; then
+    echo "usage: $0 <kernel binary> [log]" >&2
+    exit 1
+fi
+kernel=$1
+shift
+
+sed -n 's/^ *#\([0-9][0-9]*\) \(0x[0-9a-f]*\)$/\2/p' "$@" | addr2line -e "$kernel" -a -f -C -i -p
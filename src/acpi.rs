@@ -0,0 +1,229 @@
+// Finding ACPI tables, so far only the MADT, which describes the interrupt controllers.
+//
+// The tables are read through the bootloader's mapping of physical memory, so `memory::init` has to
+// run first. Only the fields the kernel needs are decoded.
+use crate::memory::phys_to_virt;
+use core::{mem, ptr};
+use x86_64::PhysAddr;
+
+const MAX_IO_APICS: usize = 8;
+const MAX_OVERRIDES: usize = 16;
+
+// MADT entry types
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_OVERRIDE: u8 = 2;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+// The RSDP, which points to the root table. The fields behind `rsdt_address` only exist from
+// revision 2 on.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+// Header every table starts with
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub addr: PhysAddr,
+    // First global system interrupt (GSI) the I/O APIC handles
+    pub gsi_base: u32,
+}
+
+// Where an ISA IRQ is connected to the I/O APICs and how it signals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaIrq {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+// The parts of the MADT the APIC driver needs
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub local_apic: PhysAddr,
+    // Whether the machine also has the legacy 8259 PICs
+    pub has_pics: bool,
+    pub cpus: usize,
+    io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    overrides: [Option<IsaIrq>; MAX_OVERRIDES],
+}
+
+impl Madt {
+    pub fn io_apics(&self) -> impl Iterator<Item = &IoApicInfo> {
+        self.io_apics.iter().flatten()
+    }
+
+    // Where the ISA IRQ is connected, the GSI with the same number (active high and edge
+    // triggered like on the ISA bus) unless the MADT overrides it. None if that GSI was given to
+    // another IRQ instead, like GSI 2 which usually carries the timer IRQ 0.
+    pub fn isa_irq(&self, irq: u8) -> Option<IsaIrq> {
+        let mut overrides = self.overrides.iter().flatten();
+        if let Some(&overridden) = overrides.clone().find(|o| o.irq == irq) {
+            return Some(overridden);
+        }
+        if overrides.any(|o| o.gsi == u32::from(irq)) {
+            return None;
+        }
+        Some(IsaIrq {
+            irq,
+            gsi: u32::from(irq),
+            active_low: false,
+            level_triggered: false,
+        })
+    }
+}
+
+// Finds and decodes the MADT, None if the firmware has no (valid) one
+pub fn madt() -> Option<Madt> {
+    let table = find_table(*b"APIC")?;
+    let header: SdtHeader = unsafe { read(table) };
+    let end = table + u64::from(header.length);
+
+    let mut madt = Madt {
+        local_apic: PhysAddr::new(u64::from(unsafe { read::<u32>(table + 36u64) })),
+        has_pics: unsafe { read::<u32>(table + 40u64) } & 1 != 0,
+        cpus: 0,
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; MAX_OVERRIDES],
+    };
+    let (mut io_apics, mut overrides) = (0, 0);
+    let mut entry = table + 44u64;
+    while entry + 2u64 <= end {
+        let (kind, length) = unsafe { (read::<u8>(entry), read::<u8>(entry + 1u64)) };
+        if length < 2 || entry + u64::from(length) > end {
+            break;
+        }
+        match kind {
+            LOCAL_APIC => {
+                // bit 0: enabled, bit 1: can be enabled later
+                if unsafe { read::<u32>(entry + 4u64) } & 0b11 != 0 {
+                    madt.cpus += 1;
+                }
+            }
+            IO_APIC if io_apics < MAX_IO_APICS => {
+                madt.io_apics[io_apics] = Some(IoApicInfo {
+                    id: unsafe { read(entry + 2u64) },
+                    addr: PhysAddr::new(u64::from(unsafe { read::<u32>(entry + 4u64) })),
+                    gsi_base: unsafe { read(entry + 8u64) },
+                });
+                io_apics += 1;
+            }
+            INTERRUPT_OVERRIDE if overrides < MAX_OVERRIDES => {
+                // polarity in bits 0 and 1, trigger mode in bits 2 and 3, 0b11 meaning active low
+                // and level triggered respectively
+                let flags = unsafe { read::<u16>(entry + 8u64) };
+                madt.overrides[overrides] = Some(IsaIrq {
+                    irq: unsafe { read(entry + 3u64) },
+                    gsi: unsafe { read(entry + 4u64) },
+                    active_low: flags & 0b11 == 0b11,
+                    level_triggered: (flags >> 2) & 0b11 == 0b11,
+                });
+                overrides += 1;
+            }
+            LOCAL_APIC_ADDRESS_OVERRIDE => {
+                madt.local_apic = PhysAddr::new(unsafe { read(entry + 4u64) });
+            }
+            _ => {}
+        }
+        entry += u64::from(length);
+    }
+    Some(madt)
+}
+
+// Returns the physical address of the table with the given signature, None if there is none or
+// its checksum is wrong
+pub fn find_table(signature: [u8; 4]) -> Option<PhysAddr> {
+    let rsdp: Rsdp = unsafe { read(find_rsdp()?) };
+    // the XSDT holds 64-bit pointers, the RSDT of revision 1 32-bit ones
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(u64::from(rsdp.rsdt_address)), 4)
+    };
+    let root_length = u64::from(valid_table(root)?.length);
+    let header_size = mem::size_of::<SdtHeader>() as u64;
+
+    let entries = (root_length.saturating_sub(header_size)) / entry_size;
+    (0..entries)
+        .map(|i| {
+            let entry = root + header_size + i * entry_size;
+            match entry_size {
+                8 => unsafe { read::<u64>(entry) },
+                _ => u64::from(unsafe { read::<u32>(entry) }),
+            }
+        })
+        .map(PhysAddr::new)
+        .find(|&table| {
+            let found = valid_table(table).map(|header| header.signature);
+            found == Some(signature)
+        })
+}
+
+// Looks for the RSDP in the first KiB of the EBDA, whose segment the BIOS stores at 0x40e, and in
+// the BIOS ROM area
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = u64::from(unsafe { read::<u16>(PhysAddr::new(0x40e)) }) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+    areas
+        .iter()
+        .flat_map(|&(start, end)| (start..end).step_by(16))
+        .map(PhysAddr::new)
+        .find(|&addr| is_rsdp(addr))
+}
+
+fn is_rsdp(addr: PhysAddr) -> bool {
+    let rsdp: Rsdp = unsafe { read(addr) };
+    let signature = rsdp.signature;
+    if signature != *b"RSD PTR " || !checksum_ok(addr, 20) {
+        return false;
+    }
+    rsdp.revision < 2 || checksum_ok(addr, u64::from(rsdp.length))
+}
+
+// Reads the header of the table at `addr` if the table's checksum is right
+fn valid_table(addr: PhysAddr) -> Option<SdtHeader> {
+    let header: SdtHeader = unsafe { read(addr) };
+    let length = u64::from(header.length);
+    if length < mem::size_of::<SdtHeader>() as u64 || !checksum_ok(addr, length) {
+        return None;
+    }
+    Some(header)
+}
+
+// ACPI structures are valid if all their bytes add up to 0
+fn checksum_ok(addr: PhysAddr, length: u64) -> bool {
+    let sum = (0..length).fold(0u8, |sum, i| sum.wrapping_add(unsafe { read(addr + i) }));
+    sum == 0
+}
+
+unsafe fn read<T: Copy>(addr: PhysAddr) -> T {
+    ptr::read_unaligned(phys_to_virt(addr).as_ptr())
+}
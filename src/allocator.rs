@@ -1,27 +1,90 @@
+use crate::memory::{
+    self, frames::PageTableFrames, huge, protect,
+    vma::{VmaKind, KERNEL_VMAS},
+};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86_64::{
 	structures::paging::{
-		mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+		mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
 	},
 	VirtAddr,
 };
 
+pub mod buddy;
 pub mod bump;
+pub mod fixed_size_block;
+#[cfg(feature = "heap-guard")]
+pub mod guard;
 pub mod linked_list;
+pub mod slab;
+#[cfg(feature = "heap-debug")]
+pub mod tracking;
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024;
+// Size of the virtual area reserved for the heap, it can't grow any further than this
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024;
+// Minimum amount of memory mapped each time the heap grows
+const HEAP_GROW_SIZE: usize = 64 * 1024;
+
+// Start of the heap area reserved by `init_heap`
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+// End of the mapped heap memory, moved up by `grow_heap`
+static HEAP_END: AtomicUsize = AtomicUsize::new(0);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+// The global allocator backend is picked at compile time through the `alloc-*` cargo features,
+// e.g. `cargo test --no-default-features --features alloc-buddy`
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block",
+    feature = "alloc-buddy"
+)))]
+compile_error!("select a heap allocator with one of the `alloc-*` features");
+
+#[cfg(any(
+    all(
+        feature = "alloc-bump",
+        any(feature = "alloc-linked-list", feature = "alloc-fixed-block", feature = "alloc-buddy")
+    ),
+    all(feature = "alloc-linked-list", any(feature = "alloc-fixed-block", feature = "alloc-buddy")),
+    all(feature = "alloc-fixed-block", feature = "alloc-buddy")
+))]
+compile_error!("only one `alloc-*` feature can be enabled, pass `--no-default-features` to replace the default");
+
+#[cfg(feature = "alloc-bump")]
+type Backend = bump::BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
+type Backend = linked_list::LinkedListAllocator;
+#[cfg(feature = "alloc-fixed-block")]
+type Backend = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "alloc-buddy")]
+type Backend = buddy::BuddyAllocator;
 
+#[cfg_attr(not(feature = "heap-guard"), global_allocator)]
+static ALLOCATOR: Locked<Backend> = Locked::new(Backend::new());
+
+// With `heap-guard` every allocation goes through the corruption checks before reaching the backend
+#[cfg(feature = "heap-guard")]
 #[global_allocator]
-static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+static GUARDED_ALLOCATOR: guard::GuardedAllocator<Locked<Backend>> =
+    guard::GuardedAllocator::new(&ALLOCATOR);
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
+    // reserve the whole area the heap may grow into, aligned to its size so that the buddy
+    // allocator's blocks are naturally aligned
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | protect::no_execute();
+    let heap_start = KERNEL_VMAS
+        .lock()
+        .reserve(HEAP_MAX_SIZE as u64, HEAP_MAX_SIZE as u64, VmaKind::Heap, flags)
+        .map_err(|_| MapToError::FrameAllocationFailed)?;
+
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
         let heap_end = heap_start + HEAP_SIZE - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
@@ -29,26 +92,154 @@ pub fn init_heap(
     };
 
     for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
-        };
+        map_heap_page(page, mapper, frame_allocator)?;
     }
 
+    let heap_start = heap_start.as_u64() as usize;
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(heap_start, HEAP_SIZE);
     }
+    HEAP_START.store(heap_start, Ordering::SeqCst);
+    HEAP_END.store(heap_start + HEAP_SIZE, Ordering::SeqCst);
 
     Ok(())
 }
 
-pub struct Dummy;
-use bump::BumpAllocator;
+// Start address of the heap, 0 before `init_heap` was called
+pub fn heap_start() -> usize {
+    HEAP_START.load(Ordering::SeqCst)
+}
 
+// Sets how large the heap may grow, in bytes, up to `HEAP_MAX_SIZE`.
+// Memory that is already mapped stays part of the heap.
+pub fn set_heap_limit(max_size: usize) {
+    HEAP_LIMIT.store(max_size.min(HEAP_MAX_SIZE), Ordering::SeqCst);
+}
 
+fn map_heap_page(
+    page: Page,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | protect::no_execute();
+    unsafe {
+        mapper.map_to(page, frame, flags, &mut PageTableFrames(frame_allocator))?.flush()
+    };
+    Ok(())
+}
+
+/*
+Maps at least `min_size` more bytes at the end of the heap through the kernel mapper
+
+Returns the start address and size of the newly mapped memory, which may be less than `min_size`
+if the frame allocator runs dry. Returns None without mapping anything if the heap isn't
+initialized or `min_size` more bytes would take it past its limit, and None if no frames are left.
+
+Called with the backend locked, so the lock order is heap, then kernel memory. Code holding the
+kernel memory must therefore never allocate, see `memory::with_kernel_memory`.
+ */
+fn grow_heap(min_size: usize) -> Option<(usize, usize)> {
+    let heap_end = HEAP_END.load(Ordering::SeqCst);
+    let heap_limit = heap_start() + HEAP_LIMIT.load(Ordering::SeqCst);
+    if heap_end == 0 || heap_end.saturating_add(min_size) > heap_limit {
+        return None;
+    }
+    let size = align_up(min_size.max(HEAP_GROW_SIZE), Size4KiB::SIZE as usize)
+        .min(heap_limit.saturating_sub(heap_end));
+
+    // large growths get 2 MiB pages once the heap end is aligned for them
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | protect::no_execute();
+    let mapped = memory::with_kernel_memory(|kernel| {
+        huge::map_backed_range(
+            &mut kernel.mapper,
+            VirtAddr::new(heap_end as u64),
+            size as u64,
+            flags,
+            &mut kernel.frame_allocator,
+        )
+        .bytes() as usize
+    })?;
+
+    if mapped == 0 {
+        return None;
+    }
+    HEAP_END.store(heap_end + mapped, Ordering::SeqCst);
+    Some((heap_end, mapped))
+}
+
+// Current usage of a heap, as returned by `heap_stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    // Bytes handed out to live allocations, as requested by their layouts
+    pub allocated_bytes: usize,
+    // Bytes sitting in the backend's free lists or regions
+    pub free_bytes: usize,
+    pub live_allocations: usize,
+    // Highest value `allocated_bytes` has reached
+    pub peak_allocated_bytes: usize,
+    pub largest_free_block: usize,
+    pub failed_allocations: usize,
+}
+
+// Returns the usage statistics of the global heap
+#[cfg(not(feature = "heap-guard"))]
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+// The backend also counts the headers and red zones the guard adds to every allocation, so the
+// allocated sizes are taken from the guard
+#[cfg(feature = "heap-guard")]
+pub fn heap_stats() -> HeapStats {
+    HeapStats {
+        allocated_bytes: GUARDED_ALLOCATOR.allocated_bytes(),
+        peak_allocated_bytes: GUARDED_ALLOCATOR.peak_allocated_bytes(),
+        ..ALLOCATOR.stats()
+    }
+}
+
+// Number of heap corruptions detected by the guarded allocator so far
+#[cfg(feature = "heap-guard")]
+pub fn heap_errors() -> usize {
+    GUARDED_ALLOCATOR.errors()
+}
+
+// Starts recording which allocations are made from now on, see `LeakCheck`
+#[cfg(feature = "heap-debug")]
+pub fn leak_check() -> tracking::LeakCheck {
+    tracking::LeakCheck {
+        start_id: global_records().lock().next_id(),
+    }
+}
+
+// Number of allocations the tracking table had no room for and that leak checks can't see
+#[cfg(feature = "heap-debug")]
+pub fn untracked_allocations() -> usize {
+    global_records().lock().untracked()
+}
+
+#[cfg(feature = "heap-debug")]
+fn global_records() -> &'static spin::Mutex<tracking::AllocationRecords> {
+    &ALLOCATOR.records
+}
+
+// Common interface of the heap backends, used to grow them and to gather their statistics
+pub trait HeapBackend {
+    // Allocates memory for the layout, returns a null pointer if the heap is exhausted
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    // Adds the memory region directly behind the current end of the heap.
+    // Unsafe because the caller must guarantee that the region is mapped and unused.
+    unsafe fn extend(&mut self, addr: usize, size: usize);
+
+    // Calls `f` with the size of every free block or region the backend can allocate from
+    fn for_each_free_block(&self, f: &mut dyn FnMut(usize));
+}
+
+pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
 	unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
@@ -62,20 +253,114 @@ unsafe impl GlobalAlloc for Dummy {
 
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
+    counters: HeapCounters,
+    #[cfg(feature = "heap-debug")]
+    records: spin::Mutex<tracking::AllocationRecords>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
             inner: spin::Mutex::new(inner),
+            counters: HeapCounters::new(),
+            #[cfg(feature = "heap-debug")]
+            records: spin::Mutex::new(tracking::AllocationRecords::new()),
         }
     }
 
     pub fn lock(&self) -> spin::MutexGuard<A> {
         self.inner.lock()
     }
+
+    // Updates the statistics after an allocation attempt, `ptr` is null if it failed
+    fn record_alloc(&self, ptr: *mut u8, layout: Layout) {
+        if ptr.is_null() {
+            self.counters.failed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let allocated = self.counters.allocated.fetch_add(layout.size(), Ordering::Relaxed);
+        self.counters.live.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .peak
+            .fetch_max(allocated + layout.size(), Ordering::Relaxed);
+    }
+
+    fn record_dealloc(&self, _ptr: *mut u8, layout: Layout) {
+        self.counters.allocated.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.counters.live.fetch_sub(1, Ordering::Relaxed);
+
+        #[cfg(feature = "heap-debug")]
+        self.records.lock().remove(_ptr as usize);
+    }
+}
+
+impl<A: HeapBackend> Locked<A> {
+    // Allocates from the backend, growing the heap until the allocation succeeds or the heap
+    // can't grow any further. Never inlined, so that the allocation site is a fixed number of
+    // frames up.
+    #[inline(never)]
+    fn alloc_or_grow(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "heap-debug")]
+        let site = tracking::allocation_site();
+        let mut heap = self.lock();
+        let ptr = loop {
+            let ptr = heap.allocate(layout);
+            if !ptr.is_null() {
+                break ptr;
+            }
+            match grow_heap(layout.size() + layout.align()) {
+                Some((addr, size)) => unsafe { heap.extend(addr, size) },
+                None => break ptr,
+            }
+        };
+        drop(heap);
+        self.record_alloc(ptr, layout);
+        #[cfg(feature = "heap-debug")]
+        if !ptr.is_null() {
+            self.records
+                .lock()
+                .insert(ptr as usize, layout.size(), layout.align(), site);
+        }
+        ptr
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let mut free_bytes = 0;
+        let mut largest_free_block = 0;
+        self.lock().for_each_free_block(&mut |size| {
+            free_bytes += size;
+            largest_free_block = largest_free_block.max(size);
+        });
+
+        HeapStats {
+            allocated_bytes: self.counters.allocated.load(Ordering::Relaxed),
+            free_bytes,
+            live_allocations: self.counters.live.load(Ordering::Relaxed),
+            peak_allocated_bytes: self.counters.peak.load(Ordering::Relaxed),
+            largest_free_block,
+            failed_allocations: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct HeapCounters {
+    allocated: AtomicUsize,
+    live: AtomicUsize,
+    peak: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl HeapCounters {
+    const fn new() -> Self {
+        HeapCounters {
+            allocated: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        }
+    }
 }
 
-fn align_up(addr: usize, align:usize) -> usize {
+const fn align_up(addr: usize, align:usize) -> usize {
     (addr + align - 1) & !(align-1)
 }
\ No newline at end of file
@@ -0,0 +1,175 @@
+use super::{HeapBackend, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
+
+// Smallest block handed out, large enough to hold a free list node once freed
+const MIN_BLOCK_SIZE: usize = 16;
+// Number of block orders; order `n` blocks are `MIN_BLOCK_SIZE << n` bytes large (16 B up to 2 GiB)
+const ORDERS: usize = 28;
+
+struct ListNode {
+	next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+	fn addr(&self) -> usize {
+		self as *const Self as usize
+	}
+}
+
+pub struct BuddyAllocator {
+	heap_start: usize,
+	free_lists: [Option<&'static mut ListNode>; ORDERS],
+}
+
+impl BuddyAllocator {
+	pub const fn new() -> Self {
+		const EMPTY: Option<&'static mut ListNode> = None;
+		BuddyAllocator {
+			heap_start: 0,
+			free_lists: [EMPTY; ORDERS],
+		}
+	}
+
+	// Initialize the allocator with the given heap bounds.
+	//
+	// Unsafe because the caller must guarantee that the given heap bounds are valid and unused.
+	// Buddy addresses are computed relative to `heap_start`, so it should be aligned to the
+	// largest alignment callers will request.
+	pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+		self.heap_start = heap_start;
+		self.add_region(heap_start, heap_size);
+	}
+
+	// Splits the region into the largest naturally aligned blocks that fit and frees them
+	unsafe fn add_region(&mut self, addr: usize, size: usize) {
+		assert_eq!(addr % MIN_BLOCK_SIZE, 0);
+
+		let mut offset = addr - self.heap_start;
+		let end = offset + size;
+		while end - offset >= MIN_BLOCK_SIZE {
+			let mut order = ORDERS - 1;
+			while block_size(order) > end - offset || offset % block_size(order) != 0 {
+				order -= 1;
+			}
+			self.free_block(self.heap_start + offset, order);
+			offset += block_size(order);
+		}
+	}
+
+	// Takes a block of the given order, splitting larger blocks if necessary
+	unsafe fn alloc_block(&mut self, order: usize) -> Option<usize> {
+		let mut current = (order..ORDERS).find(|&o| self.free_lists[o].is_some())?;
+		let block = self.pop(current)?;
+
+		// put the upper halves of the split block back on the smaller free lists
+		while current > order {
+			current -= 1;
+			self.push(current, block + block_size(current));
+		}
+		Some(block)
+	}
+
+	// Returns a block to its free list, merging it with its buddy for as long as possible
+	unsafe fn free_block(&mut self, mut addr: usize, mut order: usize) {
+		while order + 1 < ORDERS {
+			let buddy = self.heap_start + ((addr - self.heap_start) ^ block_size(order));
+			if !self.remove(order, buddy) {
+				break;
+			}
+			addr = addr.min(buddy);
+			order += 1;
+		}
+		self.push(order, addr);
+	}
+
+	unsafe fn push(&mut self, order: usize, addr: usize) {
+		let node = ListNode {
+			next: self.free_lists[order].take(),
+		};
+		let node_ptr = addr as *mut ListNode;
+		node_ptr.write(node);
+		self.free_lists[order] = Some(&mut *node_ptr);
+	}
+
+	fn pop(&mut self, order: usize) -> Option<usize> {
+		let node = self.free_lists[order].take()?;
+		self.free_lists[order] = node.next.take();
+		Some(node.addr())
+	}
+
+	// Removes the block at `addr` from the free list of the given order
+	// Returns false if that block is not free
+	fn remove(&mut self, order: usize, addr: usize) -> bool {
+		let mut current = &mut self.free_lists[order];
+		while current.as_ref().map_or(false, |node| node.addr() != addr) {
+			current = &mut current.as_mut().unwrap().next;
+		}
+		match current.take() {
+			Some(node) => {
+				*current = node.next.take();
+				true
+			}
+			None => false,
+		}
+	}
+}
+
+fn block_size(order: usize) -> usize {
+	MIN_BLOCK_SIZE << order
+}
+
+// Returns the smallest order whose blocks satisfy both the size and alignment of the layout
+fn order_for(layout: &Layout) -> Option<usize> {
+	let size = layout
+		.size()
+		.max(layout.align())
+		.max(MIN_BLOCK_SIZE)
+		.next_power_of_two();
+	let order = (size.trailing_zeros() - MIN_BLOCK_SIZE.trailing_zeros()) as usize;
+	if order < ORDERS {
+		Some(order)
+	} else {
+		None
+	}
+}
+
+impl HeapBackend for BuddyAllocator {
+	fn allocate(&mut self, layout: Layout) -> *mut u8 {
+		let order = match order_for(&layout) {
+			Some(order) => order,
+			None => return ptr::null_mut(),
+		};
+		match unsafe { self.alloc_block(order) } {
+			Some(addr) => addr as *mut u8,
+			None => ptr::null_mut(),
+		}
+	}
+
+	unsafe fn extend(&mut self, addr: usize, size: usize) {
+		self.add_region(addr, size);
+	}
+
+	fn for_each_free_block(&self, f: &mut dyn FnMut(usize)) {
+		for (order, head) in self.free_lists.iter().enumerate() {
+			let mut current = head.as_deref();
+			while let Some(node) = current {
+				f(block_size(order));
+				current = node.next.as_deref();
+			}
+		}
+	}
+}
+
+unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		self.alloc_or_grow(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		debug_assert!(mem::size_of::<ListNode>() <= MIN_BLOCK_SIZE);
+		let order = order_for(&layout).expect("dealloc of a layout that alloc rejected");
+		self.lock().free_block(ptr as usize, order);
+		self.record_dealloc(ptr, layout);
+	}
+}
@@ -1,4 +1,4 @@
-use super::{align_up, Locked};
+use super::{align_up, HeapBackend, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -26,31 +26,47 @@ impl BumpAllocator {
 	}
 }
 
-unsafe impl GlobalAlloc for Locked<BumpAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut bump = self.lock(); // mutable reference!
-
-        let alloc_start = align_up(bump.next, layout.align());
+impl HeapBackend for BumpAllocator {
+	fn allocate(&mut self, layout: Layout) -> *mut u8 {
+		let alloc_start = align_up(self.next, layout.align());
 		let alloc_end = match alloc_start.checked_add(layout.size()){
 			Some(end) => end,
 			None => return ptr::null_mut(),
 		};
 
-		if alloc_end > bump.heap_end {
+		if alloc_end > self.heap_end {
 			ptr::null_mut() // OOM
 		} else {
-			bump.next = alloc_end;
-			bump.allocations += 1;
+			self.next = alloc_end;
+			self.allocations += 1;
 			alloc_start as *mut u8
 		}
+	}
+
+	unsafe fn extend(&mut self, addr: usize, size: usize) {
+		// the heap only ever grows at its end, so the new memory simply moves the end up
+		assert_eq!(addr, self.heap_end);
+		self.heap_end += size;
+	}
+
+	fn for_each_free_block(&self, f: &mut dyn FnMut(usize)) {
+		f(self.heap_end - self.next);
+	}
+}
+
+unsafe impl GlobalAlloc for Locked<BumpAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_or_grow(layout)
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut bump = self.lock(); // mutable reference!
 
 		bump.allocations -= 1;
 		if bump.allocations == 0 {
 			bump.next = bump.heap_start;
 		}
+		drop(bump);
+		self.record_dealloc(ptr, layout);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,116 @@
+use super::{linked_list::LinkedListAllocator, HeapBackend, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::mem;
+
+// The block sizes to use.
+// The sizes must each be a power of 2 because they are also used as the block alignment
+// (alignments must always be powers of 2).
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+// Head of a free list. Unlike the linked list allocator, the size isn't stored in the node
+// because every block in a given list has the same size.
+struct ListNode {
+	next: Option<&'static mut ListNode>,
+}
+
+pub struct FixedSizeBlockAllocator {
+	list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+	fallback_allocator: LinkedListAllocator,
+}
+
+impl FixedSizeBlockAllocator {
+	// Creates an empty FixedSizeBlockAllocator
+	pub const fn new() -> Self {
+		const EMPTY: Option<&'static mut ListNode> = None;
+		FixedSizeBlockAllocator {
+			list_heads: [EMPTY; BLOCK_SIZES.len()],
+			fallback_allocator: LinkedListAllocator::new(),
+		}
+	}
+
+	// Initialize the allocator with the given heap bounds.
+	//
+	// This function is unsafe because the caller must guarantee that the given heap bounds
+	// are valid and that the heap is unused. This method must be called only once.
+	pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+		self.fallback_allocator.init(heap_start, heap_size);
+	}
+
+	// Allocates using the fallback allocator
+	fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+		self.fallback_allocator.allocate(layout)
+	}
+}
+
+// Choose an appropriate block size for the given layout
+// Returns an index into the `BLOCK_SIZES` array
+fn list_index(layout: &Layout) -> Option<usize> {
+	let required_block_size = layout.size().max(layout.align());
+	BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+impl HeapBackend for FixedSizeBlockAllocator {
+	fn allocate(&mut self, layout: Layout) -> *mut u8 {
+		match list_index(&layout) {
+			Some(index) => {
+				match self.list_heads[index].take() {
+					Some(node) => {
+						// pop the first block off the matching free list
+						self.list_heads[index] = node.next.take();
+						node as *mut ListNode as *mut u8
+					}
+					None => {
+						// no block exists in list => allocate new block
+						let block_size = BLOCK_SIZES[index];
+						// only works if all block sizes are a power of 2
+						let block_align = block_size;
+						let layout = Layout::from_size_align(block_size, block_align).unwrap();
+						self.fallback_alloc(layout)
+					}
+				}
+			}
+			None => self.fallback_alloc(layout),
+		}
+	}
+
+	unsafe fn extend(&mut self, addr: usize, size: usize) {
+		self.fallback_allocator.extend(addr, size);
+	}
+
+	fn for_each_free_block(&self, f: &mut dyn FnMut(usize)) {
+		for (index, head) in self.list_heads.iter().enumerate() {
+			let mut current = head.as_deref();
+			while let Some(node) = current {
+				f(BLOCK_SIZES[index]);
+				current = node.next.as_deref();
+			}
+		}
+		self.fallback_allocator.for_each_free_block(f);
+	}
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		self.alloc_or_grow(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		let mut allocator = self.lock();
+		match list_index(&layout) {
+			Some(index) => {
+				let new_node = ListNode {
+					next: allocator.list_heads[index].take(),
+				};
+				// verify that block has size and alignment required for storing node
+				assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+				assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+				let new_node_ptr = ptr as *mut ListNode;
+				new_node_ptr.write(new_node);
+				allocator.list_heads[index] = Some(&mut *new_node_ptr);
+			}
+			None => allocator.fallback_allocator.deallocate(ptr, layout),
+		}
+		drop(allocator);
+		self.record_dealloc(ptr, layout);
+	}
+}
@@ -0,0 +1,174 @@
+// Debug wrapper that catches heap corruption, compiled in with the `heap-guard` feature.
+//
+// Every allocation is surrounded by red zones and preceded by a header recording its layout:
+//
+//     | slack | header | front red zone | user data | rear red zone |
+//
+// The slack in front keeps the header clear of the free list node the wrapped allocator writes
+// into the start of freed blocks, so double frees can still be recognized afterwards (as long as
+// the memory hasn't been handed out again).
+use crate::serial_println;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const SLACK_SIZE: usize = 16;
+const RED_ZONE_SIZE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xfd;
+// Freed memory is filled with this, so a use after free reads obviously bogus values
+const POISON_BYTE: u8 = 0x6b;
+
+const MAGIC_LIVE: u64 = 0xa110_ca7e_d0d0_cafe;
+const MAGIC_FREED: u64 = 0xdead_f4ee_d0d0_cafe;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    align: usize,
+}
+
+const HEADER_SIZE: usize = mem::size_of::<Header>();
+
+pub struct GuardedAllocator<A: 'static> {
+    inner: &'static A,
+    errors: AtomicUsize,
+    // Bytes of user data in live allocations and their peak, the wrapped allocator only sees
+    // the blocks including red zones and headers
+    allocated: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl<A> GuardedAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        GuardedAllocator {
+            inner,
+            errors: AtomicUsize::new(0),
+            allocated: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<A: GlobalAlloc> GuardedAllocator<A> {
+    // Number of corruptions, double frees and layout mismatches detected so far
+    pub fn errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    // Bytes requested by the live allocations, without the guard's overhead
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    // Highest value `allocated_bytes` has reached
+    pub fn peak_allocated_bytes(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    fn report(&self, args: core::fmt::Arguments) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        serial_println!("HEAP CORRUPTION: {}", args);
+    }
+
+    // Checks that every byte of the red zone starting at `addr` is intact
+    unsafe fn check_red_zone(&self, user: *mut u8, addr: *mut u8, which: &str) {
+        for offset in 0..RED_ZONE_SIZE {
+            let byte = addr.add(offset);
+            if byte.read() != RED_ZONE_BYTE {
+                self.report(format_args!(
+                    "{} red zone of block {:p} overwritten at {:p}",
+                    which, user, byte
+                ));
+                return;
+            }
+        }
+    }
+}
+
+// Distance from the start of the underlying block to the user data
+fn prefix_size(align: usize) -> usize {
+    super::align_up(SLACK_SIZE + HEADER_SIZE + RED_ZONE_SIZE, align)
+}
+
+// Layout of the underlying block holding a guarded allocation of the given size and alignment
+fn outer_layout(size: usize, align: usize) -> Option<Layout> {
+    let align = align.max(mem::align_of::<Header>());
+    let size = prefix_size(align)
+        .checked_add(size)?
+        .checked_add(RED_ZONE_SIZE)?;
+    Layout::from_size_align(size, align).ok()
+}
+
+unsafe fn header(user: *mut u8) -> *mut Header {
+    user.sub(RED_ZONE_SIZE + HEADER_SIZE) as *mut Header
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for GuardedAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let outer = match outer_layout(layout.size(), layout.align()) {
+            Some(outer) => outer,
+            None => return ptr::null_mut(),
+        };
+        let base = self.inner.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+
+        let user = base.add(prefix_size(outer.align()));
+        header(user).write(Header {
+            magic: MAGIC_LIVE,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        ptr::write_bytes(user.sub(RED_ZONE_SIZE), RED_ZONE_BYTE, RED_ZONE_SIZE);
+        ptr::write_bytes(user.add(layout.size()), RED_ZONE_BYTE, RED_ZONE_SIZE);
+
+        let allocated = self.allocated.fetch_add(layout.size(), Ordering::Relaxed);
+        self.peak
+            .fetch_max(allocated + layout.size(), Ordering::Relaxed);
+        user
+    }
+
+    unsafe fn dealloc(&self, user: *mut u8, layout: Layout) {
+        let header = header(user);
+        match (*header).magic {
+            MAGIC_LIVE => {}
+            MAGIC_FREED => {
+                self.report(format_args!("double free of block {:p}", user));
+                return;
+            }
+            magic => {
+                // leak the block rather than hand a bogus layout to the wrapped allocator
+                self.report(format_args!(
+                    "header of block {:p} overwritten (magic {:#x})",
+                    user, magic
+                ));
+                return;
+            }
+        }
+
+        let Header { size, align, .. } = header.read();
+        if size != layout.size() || align != layout.align() {
+            self.report(format_args!(
+                "block {:p} allocated with size {} align {} but freed with size {} align {}",
+                user,
+                size,
+                align,
+                layout.size(),
+                layout.align()
+            ));
+        }
+        self.check_red_zone(user, user.sub(RED_ZONE_SIZE), "front");
+        self.check_red_zone(user, user.add(size), "rear");
+
+        // free with the layout the block was really allocated with
+        let outer = outer_layout(size, align).unwrap();
+        ptr::write_bytes(user, POISON_BYTE, size);
+        (*header).magic = MAGIC_FREED;
+        self.allocated.fetch_sub(size, Ordering::Relaxed);
+        self.inner
+            .dealloc(user.sub(prefix_size(outer.align())), outer);
+    }
+}
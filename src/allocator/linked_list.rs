@@ -1,5 +1,6 @@
-use super::align_up;
-use core::mem;
+use super::{align_up, HeapBackend, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
 
 
 pub struct LinkedListAllocator {
@@ -17,19 +18,39 @@ impl LinkedListAllocator{
 		self.add_free_region(heap_start, heap_size);
 	}
 
-	// Adds the given memory region to the front of the list
+	// Adds the given memory region to the list, keeping the list sorted by address
+	// and merging the region with its neighbours when they are adjacent
 	unsafe fn add_free_region(&mut self, addr: usize, size: usize){
 		// ensure that the freed region is capable of holding ListNode
 		assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
 		assert!(size >= mem::size_of::<ListNode>());
 
-		// create a new list node and append it at the start of the list
-		let mut node = ListNode::new(size);
-		node.next = self.head.next.take();
-		let node_ptr = addr as *mut ListNode;
-		node_ptr.write(node);
-		self.head.next = Some(&mut *node_ptr)
+		// find the last node that starts before the freed region
+		let mut current = &mut self.head;
+		while current.next.as_ref().map_or(false, |next| next.start_addr() < addr) {
+			current = current.next.as_mut().unwrap();
+		}
+
+		// merge with the following region if it starts right where the freed region ends
+		let mut size = size;
+		if current.next.as_ref().map_or(false, |next| next.start_addr() == addr + size) {
+			let next = current.next.take().unwrap();
+			size += next.size;
+			current.next = next.next.take();
+		}
 
+		// merge with the preceding region if it ends right where the freed region starts.
+		// The head is a zero-sized dummy node that never describes free memory.
+		if current.size != 0 && current.end_addr() == addr {
+			current.size += size;
+		} else {
+			// create a new list node and link it in after the preceding region
+			let mut node = ListNode::new(size);
+			node.next = current.next.take();
+			let node_ptr = addr as *mut ListNode;
+			node_ptr.write(node);
+			current.next = Some(&mut *node_ptr)
+		}
 	}
 
 	// Looks for free region and removes it from the list to use it for allocation
@@ -62,7 +83,13 @@ impl LinkedListAllocator{
 	fn alloc_from_region(region: &ListNode, size: usize, align: usize)
 	-> Result<usize, ()>
 	{
-		let alloc_start = align_up(region.start_addr(), align);
+		let mut alloc_start = align_up(region.start_addr(), align);
+		// padding in front of the allocation goes back to the list, so it must fit a node
+		if alloc_start > region.start_addr()
+			&& alloc_start - region.start_addr() < mem::size_of::<ListNode>()
+		{
+			alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+		}
 		let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
 		if alloc_end > region.end_addr(){
@@ -77,6 +104,100 @@ impl LinkedListAllocator{
 
 		Ok(alloc_start)
 	}
+
+	// Returns memory allocated with the given layout to the free list
+	pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+		// perform layout adjustments
+		let (size, _) = LinkedListAllocator::size_align(layout);
+
+		self.add_free_region(ptr as usize, size)
+	}
+
+	// Adjust the given layout so that the resulting allocated memory region
+	// is also capable of storing a `ListNode` once it is freed
+	// Returns the adjusted size and alignment as a (size, align) tuple
+	fn size_align(layout: Layout) -> (usize, usize) {
+		let layout = layout
+			.align_to(mem::align_of::<ListNode>())
+			.expect("adjusting alignment failed")
+			.pad_to_align();
+		let size = layout.size().max(mem::size_of::<ListNode>());
+		(size, layout.align())
+	}
+}
+
+impl HeapBackend for LinkedListAllocator {
+	fn allocate(&mut self, layout: Layout) -> *mut u8 {
+		// perform layout adjustments
+		let (size, align) = LinkedListAllocator::size_align(layout);
+
+		if let Some((region, alloc_start)) = self.find_region(size, align) {
+			// read the region bounds before its node gets overwritten below
+			let region_start = region.start_addr();
+			let region_end = region.end_addr();
+			let alloc_end = alloc_start.checked_add(size).expect("overflow");
+
+			unsafe {
+				// give the padding in front of an over-aligned allocation back to the list
+				let padding = alloc_start - region_start;
+				if padding > 0 {
+					self.add_free_region(region_start, padding);
+				}
+
+				let excess_size = region_end - alloc_end;
+				if excess_size > 0 {
+					self.add_free_region(alloc_end, excess_size);
+				}
+			}
+			alloc_start as *mut u8
+		} else {
+			ptr::null_mut()
+		}
+	}
+
+	unsafe fn extend(&mut self, addr: usize, size: usize) {
+		self.add_free_region(addr, size);
+	}
+
+	fn for_each_free_block(&self, f: &mut dyn FnMut(usize)) {
+		let mut current = self.head.next.as_deref();
+		while let Some(region) = current {
+			f(region.size);
+			current = region.next.as_deref();
+		}
+	}
+}
+
+unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		self.alloc_or_grow(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		self.lock().deallocate(ptr, layout);
+		self.record_dealloc(ptr, layout);
+	}
+}
+
+#[test_case]
+fn test_front_padding_is_not_lost() {
+	#[repr(align(64))]
+	struct Buffer([u8; 256]);
+	static mut BUFFER: Buffer = Buffer([0; 256]);
+
+	// a region starting 8 bytes before a 32 byte boundary, too little to hold a node in front
+	let mut allocator = LinkedListAllocator::new();
+	let start = unsafe { BUFFER.0.as_mut_ptr() as usize } + 24;
+	unsafe { allocator.init(start, 128) };
+	let layout = Layout::from_size_align(16, 32).unwrap();
+	let ptr = allocator.allocate(layout);
+	assert!(!ptr.is_null());
+	assert_eq!(ptr as usize % 32, 0);
+	unsafe { allocator.deallocate(ptr, layout) };
+
+	let mut free = 0;
+	allocator.for_each_free_block(&mut |size| free += size);
+	assert_eq!(free, 128);
 }
 
 struct ListNode {
@@ -0,0 +1,263 @@
+// Slab caches for kernel objects of a single type.
+//
+// Each slab is one 4 KiB frame taken straight from the frame allocator, so slab objects don't
+// compete with general `Box` traffic on the heap. A slab starts with a `SlabHeader` holding a
+// bitmap of its free slots, followed by the equally sized object slots.
+//
+// Like in other slab allocators, the constructor runs once per slot when a slab is created and
+// freed objects go back to the cache as they are, so callers should return them in a reusable
+// state. The destructor and `Drop` only run when an empty slab is reclaimed.
+use super::{align_up, Locked};
+use crate::memory;
+use core::{
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+
+const SLAB_SIZE: usize = 4096;
+const BITMAP_WORDS: usize = SLAB_SIZE / 64;
+// Empty slabs kept around for future allocations instead of being reclaimed right away
+const MAX_EMPTY_SLABS: usize = 1;
+
+struct SlabHeader {
+    prev: Option<NonNull<SlabHeader>>,
+    next: Option<NonNull<SlabHeader>>,
+    frame: PhysFrame,
+    free_count: usize,
+    // set bits mark free slots
+    free_slots: [u64; BITMAP_WORDS],
+}
+
+pub struct SlabCache<T> {
+    partial: Option<NonNull<SlabHeader>>,
+    full: Option<NonNull<SlabHeader>>,
+    empty: Option<NonNull<SlabHeader>>,
+    empty_count: usize,
+    slab_count: usize,
+    constructor: fn() -> T,
+    destructor: Option<fn(&mut T)>,
+}
+
+// The slabs are only reachable through the cache, so it can move between threads with its objects
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    // zero-sized types still get a byte each so that every slot has its own address
+    const OBJECT_SIZE: usize = align_up(
+        if mem::size_of::<T>() == 0 { 1 } else { mem::size_of::<T>() },
+        mem::align_of::<T>(),
+    );
+    const OBJECTS_OFFSET: usize = align_up(mem::size_of::<SlabHeader>(), mem::align_of::<T>());
+    const CAPACITY: usize = (SLAB_SIZE - Self::OBJECTS_OFFSET) / Self::OBJECT_SIZE;
+
+    // Creates an empty cache whose objects are initialized by `constructor`
+    pub fn new(constructor: fn() -> T) -> Self {
+        SlabCache {
+            partial: None,
+            full: None,
+            empty: None,
+            empty_count: 0,
+            slab_count: 0,
+            constructor,
+            destructor: None,
+        }
+    }
+
+    // Creates an empty cache that also calls `destructor` on every object of a reclaimed slab
+    pub fn with_destructor(constructor: fn() -> T, destructor: fn(&mut T)) -> Self {
+        let mut cache = Self::new(constructor);
+        cache.destructor = Some(destructor);
+        cache
+    }
+
+    // Number of frames the cache currently holds
+    pub fn slab_count(&self) -> usize {
+        self.slab_count
+    }
+
+    // Takes a free object, creating a new slab if none is left
+    pub fn allocate(&mut self) -> Option<NonNull<T>> {
+        let slab = match self.partial.or(self.empty) {
+            Some(slab) => slab,
+            None => self.grow()?,
+        };
+
+        unsafe {
+            let header = &mut *slab.as_ptr();
+            let word = header.free_slots.iter().position(|&w| w != 0).unwrap();
+            let bit = header.free_slots[word].trailing_zeros() as usize;
+            header.free_slots[word] &= !(1 << bit);
+            self.set_free_count(slab, header.free_count - 1);
+
+            Some(Self::object(slab, word * 64 + bit))
+        }
+    }
+
+    /*
+    Returns an object to the cache
+
+    Unsafe because the caller must guarantee that `object` was allocated from this cache and is
+    no longer used.
+     */
+    pub unsafe fn free(&mut self, object: NonNull<T>) {
+        let slab = Self::slab_of(object);
+        let slot = (object.as_ptr() as usize - slab.as_ptr() as usize - Self::OBJECTS_OFFSET)
+            / Self::OBJECT_SIZE;
+
+        let header = &mut *slab.as_ptr();
+        let mask = 1 << (slot % 64);
+        debug_assert_eq!(header.free_slots[slot / 64] & mask, 0, "double free of {:p}", object);
+        header.free_slots[slot / 64] |= mask;
+        self.set_free_count(slab, header.free_count + 1);
+
+        if self.empty_count > MAX_EMPTY_SLABS {
+            self.reclaim_one();
+        }
+    }
+
+    // Gives the frames of all empty slabs back to the frame allocator
+    pub fn reclaim(&mut self) {
+        while self.empty.is_some() {
+            unsafe { self.reclaim_one() };
+        }
+    }
+
+    // Allocates a frame for a new slab and constructs all of its objects
+    fn grow(&mut self) -> Option<NonNull<SlabHeader>> {
+        assert!(Self::CAPACITY > 0, "object too large for a slab");
+        let frame = memory::with_kernel_memory(|kernel| kernel.frame_allocator.allocate_frame())??;
+        let slab = NonNull::new(memory::phys_to_virt(frame.start_address()).as_mut_ptr())?;
+
+        unsafe {
+            let mut free_slots = [0; BITMAP_WORDS];
+            for slot in 0..Self::CAPACITY {
+                free_slots[slot / 64] |= 1 << (slot % 64);
+                Self::object(slab, slot).as_ptr().write((self.constructor)());
+            }
+            slab.as_ptr().write(SlabHeader {
+                prev: None,
+                next: None,
+                frame,
+                free_count: Self::CAPACITY,
+                free_slots,
+            });
+            list_push(&mut self.empty, slab);
+        }
+        self.empty_count += 1;
+        self.slab_count += 1;
+        Some(slab)
+    }
+
+    // Destroys the objects of an empty slab and frees its frame
+    unsafe fn reclaim_one(&mut self) {
+        let slab = match self.empty {
+            Some(slab) => slab,
+            None => return,
+        };
+        list_remove(&mut self.empty, slab);
+        self.empty_count -= 1;
+        self.slab_count -= 1;
+
+        for slot in 0..Self::CAPACITY {
+            let object = Self::object(slab, slot).as_ptr();
+            if let Some(destructor) = self.destructor {
+                destructor(&mut *object);
+            }
+            ptr::drop_in_place(object);
+        }
+
+        let frame = (*slab.as_ptr()).frame;
+        memory::with_kernel_memory(|kernel| kernel.frame_allocator.deallocate_frame(frame));
+    }
+
+    // Updates the free count of a slab, moving it to the list matching its new state
+    unsafe fn set_free_count(&mut self, slab: NonNull<SlabHeader>, free_count: usize) {
+        let old_count = (*slab.as_ptr()).free_count;
+        (*slab.as_ptr()).free_count = free_count;
+
+        list_remove(self.list_for(old_count), slab);
+        list_push(self.list_for(free_count), slab);
+        if old_count == Self::CAPACITY {
+            self.empty_count -= 1;
+        }
+        if free_count == Self::CAPACITY {
+            self.empty_count += 1;
+        }
+    }
+
+    fn list_for(&mut self, free_count: usize) -> &mut Option<NonNull<SlabHeader>> {
+        if free_count == 0 {
+            &mut self.full
+        } else if free_count == Self::CAPACITY {
+            &mut self.empty
+        } else {
+            &mut self.partial
+        }
+    }
+
+    fn object(slab: NonNull<SlabHeader>, slot: usize) -> NonNull<T> {
+        let addr = slab.as_ptr() as usize + Self::OBJECTS_OFFSET + slot * Self::OBJECT_SIZE;
+        NonNull::new(addr as *mut T).unwrap()
+    }
+
+    fn slab_of(object: NonNull<T>) -> NonNull<SlabHeader> {
+        let addr = object.as_ptr() as usize & !(SLAB_SIZE - 1);
+        NonNull::new(addr as *mut SlabHeader).unwrap()
+    }
+}
+
+impl<T: 'static> Locked<SlabCache<T>> {
+    // Takes an object from the cache, it goes back to the cache when the returned box is dropped
+    pub fn alloc(&'static self) -> Option<SlabBox<T>> {
+        let ptr = self.lock().allocate()?;
+        Some(SlabBox { ptr, cache: self })
+    }
+}
+
+// Owning pointer to an object in a slab cache, similar to a `Box`
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static Locked<SlabCache<T>>,
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe { self.cache.lock().free(self.ptr) }
+    }
+}
+
+unsafe fn list_push(head: &mut Option<NonNull<SlabHeader>>, slab: NonNull<SlabHeader>) {
+    (*slab.as_ptr()).prev = None;
+    (*slab.as_ptr()).next = *head;
+    if let Some(old_head) = *head {
+        (*old_head.as_ptr()).prev = Some(slab);
+    }
+    *head = Some(slab);
+}
+
+unsafe fn list_remove(head: &mut Option<NonNull<SlabHeader>>, slab: NonNull<SlabHeader>) {
+    let SlabHeader { prev, next, .. } = *slab.as_ptr();
+    match prev {
+        Some(prev) => (*prev.as_ptr()).next = next,
+        None => *head = next,
+    }
+    if let Some(next) = next {
+        (*next.as_ptr()).prev = prev;
+    }
+}
@@ -0,0 +1,150 @@
+// Records of the live heap allocations, compiled in with the `heap-debug` feature.
+//
+// The table has a fixed capacity so that recording never allocates itself. Allocations made
+// while it is full aren't recorded and are counted in `untracked` instead.
+//
+// Each record keeps the return addresses of the code that made the allocation, found by walking
+// the frame pointers like `backtrace` does, so that leak reports can be symbolized with
+// `scripts/symbolize.sh`.
+use crate::backtrace::Backtrace;
+use crate::serial_println;
+
+const MAX_RECORDS: usize = 512;
+// Number of return addresses kept per allocation, enough to get past the `alloc` crate's shims
+// between the allocating code and the global allocator
+pub const SITE_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationRecord {
+    pub addr: usize,
+    pub size: usize,
+    pub align: usize,
+    // Sequence number of the allocation, increasing over the lifetime of the heap
+    pub id: u64,
+    // Return addresses of the callers of the global allocator, innermost first and 0 where the
+    // frame pointer chain ended early
+    pub site: [u64; SITE_DEPTH],
+}
+
+// Returns the allocation site of an allocation made by the caller of `Locked::alloc_or_grow`,
+// skipping `alloc_or_grow` itself and the `GlobalAlloc` implementation that called it
+#[inline(always)]
+pub(super) fn allocation_site() -> [u64; SITE_DEPTH] {
+    let mut site = [0; SITE_DEPTH];
+    for (slot, addr) in site.iter_mut().zip(Backtrace::current().frames().skip(2)) {
+        *slot = addr;
+    }
+    site
+}
+
+pub(super) struct AllocationRecords {
+    records: [Option<AllocationRecord>; MAX_RECORDS],
+    next_id: u64,
+    untracked: usize,
+}
+
+impl AllocationRecords {
+    pub(super) const fn new() -> Self {
+        AllocationRecords {
+            records: [None; MAX_RECORDS],
+            next_id: 0,
+            untracked: 0,
+        }
+    }
+
+    pub(super) fn insert(
+        &mut self,
+        addr: usize,
+        size: usize,
+        align: usize,
+        site: [u64; SITE_DEPTH],
+    ) {
+        let id = self.next_id;
+        self.next_id += 1;
+        match self.records.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => {
+                *slot = Some(AllocationRecord {
+                    addr,
+                    size,
+                    align,
+                    id,
+                    site,
+                })
+            }
+            None => self.untracked += 1,
+        }
+    }
+
+    pub(super) fn remove(&mut self, addr: usize) {
+        match self
+            .records
+            .iter_mut()
+            .find(|r| r.map_or(false, |r| r.addr == addr))
+        {
+            Some(slot) => *slot = None,
+            None => self.untracked = self.untracked.saturating_sub(1),
+        }
+    }
+
+    pub(super) fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    pub(super) fn untracked(&self) -> usize {
+        self.untracked
+    }
+
+    // The oldest live allocation made since allocation `id`, including that one
+    pub(super) fn oldest_since(&self, id: u64) -> Option<AllocationRecord> {
+        self.records
+            .iter()
+            .flatten()
+            .filter(|r| r.id >= id)
+            .min_by_key(|r| r.id)
+            .copied()
+    }
+}
+
+// Marks a point in time, every allocation made afterwards that is still alive counts as a leak.
+//
+// ```
+// let check = allocator::leak_check();
+// run_test_case();
+// check.assert_no_leaks();
+// ```
+pub struct LeakCheck {
+    pub(super) start_id: u64,
+}
+
+impl LeakCheck {
+    // Calls `f` for every allocation made since the leak check started that hasn't been freed,
+    // oldest first. The records are copied out one by one and the table isn't locked while `f`
+    // runs, so `f` may allocate.
+    pub fn for_each_leak(&self, mut f: impl FnMut(&AllocationRecord)) {
+        let oldest_since = |id| super::global_records().lock().oldest_since(id);
+        let mut id = self.start_id;
+        while let Some(record) = oldest_since(id) {
+            id = record.id + 1;
+            f(&record);
+        }
+    }
+
+    // Reports every leaked allocation over serial and panics if there is any
+    pub fn assert_no_leaks(&self) {
+        let mut leaks = 0;
+        self.for_each_leak(|record| {
+            serial_println!(
+                "leaked allocation #{}: {} bytes (align {}) at {:#x}, allocated from:",
+                record.id,
+                record.size,
+                record.align,
+                record.addr
+            );
+            for (i, addr) in record.site.iter().take_while(|&&a| a != 0).enumerate() {
+                serial_println!("  #{} {:#018x}", i, addr);
+            }
+            leaks += 1;
+        });
+        assert_eq!(leaks, 0, "{} allocations leaked", leaks);
+    }
+}
@@ -0,0 +1,108 @@
+// Stack backtraces by following the chain of saved frame pointers.
+//
+// The target spec keeps frame pointers in every function, so each frame starts with the caller's
+// RBP followed by the return address. Frames are only read if they are mapped, so a corrupted chain
+// ends the backtrace instead of faulting again.
+//
+// Every frame is printed on its own line as `  #<n> 0x<address>`, which `scripts/symbolize.sh`
+// turns into function names and source lines. Return addresses are printed minus 1, so that they
+// point into the call instruction instead of whatever follows it.
+use crate::memory;
+use core::fmt;
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+const MAX_FRAMES: usize = 32;
+
+// Set by exception handlers right before they panic, see `panic_backtrace`
+static PANIC_BACKTRACE: Mutex<Option<Backtrace>> = Mutex::new(None);
+
+// A backtrace that is walked when it is printed, so the frames have to be alive until then
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    // Address of the innermost frame, if it isn't taken from the frame at `rbp`
+    pc: Option<u64>,
+    rbp: u64,
+}
+
+impl Backtrace {
+    // The backtrace of the caller, starting at the call of this function
+    #[inline(never)]
+    pub fn current() -> Self {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        // this function's frame is gone once it returns, so start with the caller's
+        match frame_at(rbp) {
+            Some((caller_rbp, return_address)) => Backtrace {
+                pc: Some(return_address - 1),
+                rbp: caller_rbp,
+            },
+            None => Backtrace { pc: None, rbp: 0 },
+        }
+    }
+
+    // The backtrace of interrupted code, e.g. from a `TrapFrame`
+    pub fn from_registers(rip: u64, rbp: u64) -> Self {
+        Backtrace { pc: Some(rip), rbp }
+    }
+
+    // The addresses of the frames, innermost first
+    pub fn frames(&self) -> impl Iterator<Item = u64> {
+        let mut rbp = self.rbp;
+        let callers = core::iter::from_fn(move || {
+            let (caller_rbp, return_address) = frame_at(rbp)?;
+            rbp = caller_rbp;
+            Some(return_address - 1)
+        });
+        self.pc.into_iter().chain(callers).take(MAX_FRAMES)
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "backtrace:")?;
+        for (i, addr) in self.frames().enumerate() {
+            write!(f, "\n  #{} {:#018x}", i, addr)?;
+        }
+        Ok(())
+    }
+}
+
+// Makes the next panic handler print the given backtrace instead of its own. Exception handlers
+// pass the backtrace of the interrupted code, the panic handler's would start in the handler.
+pub fn set_panic_backtrace(backtrace: Backtrace) {
+    *PANIC_BACKTRACE.lock() = Some(backtrace);
+}
+
+// The backtrace for panic handlers to print: the one passed to `set_panic_backtrace`, otherwise
+// the caller's
+#[inline(always)]
+pub fn panic_backtrace() -> Backtrace {
+    match PANIC_BACKTRACE.try_lock().and_then(|mut backtrace| backtrace.take()) {
+        Some(backtrace) => backtrace,
+        None => Backtrace::current(),
+    }
+}
+
+// Reads the caller's RBP and the return address from the frame at `rbp`, None if the frame isn't
+// mapped or ends the chain
+fn frame_at(rbp: u64) -> Option<(u64, u64)> {
+    if rbp == 0 || rbp % 8 != 0 {
+        return None;
+    }
+    let words = [
+        VirtAddr::try_new(rbp).ok()?,
+        VirtAddr::try_new(rbp.checked_add(8)?).ok()?,
+    ];
+    if !words.iter().all(|&addr| memory::is_mapped(addr)) {
+        return None;
+    }
+    let (caller_rbp, return_address) = unsafe {
+        let frame = rbp as *const u64;
+        (frame.read_volatile(), frame.add(1).read_volatile())
+    };
+    if return_address == 0 {
+        return None;
+    }
+    Some((caller_rbp, return_address))
+}
@@ -1,30 +1,22 @@
+use crate::memory::{self, stack::KernelStack};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// Size of the guarded IST stacks allocated by `init_ist_stacks`
+const IST_STACK_PAGES: u64 = 5;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
+// Mutable so that the IST entries can be switched to guarded stacks once memory management is up.
+// Only written through `set_ist_stack` (and `init` before the TSS is loaded).
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &TSS }));
         (
             gdt,
             Selectors {
@@ -44,9 +36,35 @@ pub fn init() {
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
 
+    // Boot stack for the double fault handler, used until `init_ist_stacks` replaces it.
+    // It has no guard page, so an overflow tramples whatever lies below it in `.bss`.
+    {
+        const STACK_SIZE: usize = 4096 * 5;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        let stack_end = stack_start + STACK_SIZE;
+        unsafe { TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end };
+    }
+
     GDT.0.load();
     unsafe {
         set_cs(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector)
     }
 }
+
+// Points the given IST entry at a new stack top
+pub fn set_ist_stack(index: u16, stack_top: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        TSS.interrupt_stack_table[index as usize] = stack_top;
+    });
+}
+
+// Replaces the boot IST stacks with stacks that have a guard page below them and returns the new
+// double fault stack. Requires the kernel memory to be installed (see `memory::install`).
+pub fn init_ist_stacks() -> KernelStack {
+    let stack = memory::stack::alloc_stack(IST_STACK_PAGES).expect("failed to allocate IST stack");
+    set_ist_stack(DOUBLE_FAULT_IST_INDEX, stack.top());
+    stack
+}
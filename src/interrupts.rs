@@ -1,51 +1,108 @@
-use crate::{gdt, hlt_loop, print, println};
+use crate::{print, println};
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+pub mod apic;
+pub mod exceptions;
+pub mod irq;
+pub mod trap;
+
+pub use self::irq::{
+    mask_irq, register_irq, unmask_irq, unregister_irq, InterruptContext, IrqError, IrqHandler,
+};
+
+// Timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        exceptions::set_handlers(&mut idt);
+        irq::set_stubs(&mut idt);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
 
 pub fn init_idt() {
     IDT.load();
+
+    // the kernel's own drivers
+    register_irq(InterruptIndex::Timer.irq(), timer_interrupt).expect("failed to hook the timer");
+    register_irq(InterruptIndex::Keyboard.irq(), keyboard_interrupt)
+        .expect("failed to hook the keyboard");
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+// Initializes the PICs with every IRQ masked except for those that have a handler
+pub fn init_pics() {
+    unsafe {
+        PICS.lock().initialize();
+        // IRQ 2 is the cascade from the secondary PIC
+        set_pic_masks(!(1 << 2), 0xff);
+    }
+    irq::unmask_registered().expect("failed to unmask an IRQ");
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+// Which interrupt controller delivers the hardware interrupts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController {
+    Pic,
+    Apic,
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    print!(".");
+// Switches from the PICs to the APICs if the machine has them, see `apic::init`. Needs
+// `memory::install`.
+pub fn init_apic() -> InterruptController {
+    if let Err(err) = apic::init() {
+        println!("APIC unavailable ({:?}), staying with the PICs", err);
+        return InterruptController::Pic;
+    }
+    irq::unmask_registered().expect("failed to route an IRQ");
+    InterruptController::Apic
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+pub fn controller() -> InterruptController {
+    if apic::is_enabled() {
+        InterruptController::Apic
+    } else {
+        InterruptController::Pic
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn end_of_interrupt(vector: u8) {
+    match controller() {
+        InterruptController::Apic => apic::end_of_interrupt(),
+        InterruptController::Pic => unsafe { PICS.lock().notify_end_of_interrupt(vector) },
+    }
+}
+
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xa1;
+
+// The IRQ masks of the primary and secondary PIC, a set bit masks the IRQ
+unsafe fn pic_masks() -> (u8, u8) {
+    (Port::new(PIC_1_DATA).read(), Port::new(PIC_2_DATA).read())
+}
+
+unsafe fn set_pic_masks(primary: u8, secondary: u8) {
+    Port::new(PIC_1_DATA).write(primary);
+    Port::new(PIC_2_DATA).write(secondary);
+}
+
+fn timer_interrupt(_context: &InterruptContext) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    print!(".");
+}
+
+fn keyboard_interrupt(_context: &InterruptContext) {
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
     use x86_64::instructions::port::Port;
@@ -68,25 +125,11 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut Interrup
             }
         }
     }
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed address, {:?}", Cr2::read());
-    println!("Error code, {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
-}
+// The local APIC raises the spurious vector when an interrupt went away before it could be
+// delivered. It must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {}
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -106,8 +149,9 @@ impl InterruptIndex {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    // The ISA IRQ behind the interrupt
+    fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
     }
 }
 
@@ -0,0 +1,246 @@
+// Local APIC and I/O APIC driver.
+//
+// `crate::init` starts out with the legacy PICs, since the APICs can only be reached once memory
+// management is running. `init` then masks both PICs, enables the local APIC and routes the ISA
+// IRQs through the I/O APICs to the vectors the PICs used (`PIC_1_OFFSET + irq`), following the
+// interrupt source overrides of the MADT. The routed IRQs start out masked. From then on interrupts
+// are acknowledged at the local APIC.
+use super::{set_pic_masks, PIC_1_OFFSET};
+use crate::acpi::{self, Madt};
+use crate::memory::mmio::{self, CacheMode, IoMapping, IoRemapError};
+use core::arch::x86_64::__cpuid;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{registers::model_specific::Msr, PhysAddr, VirtAddr};
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+pub const ISA_IRQS: u8 = 16;
+
+const MAX_IO_APICS: usize = 8;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// local APIC registers
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TPR: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SVR: u64 = 0xf0;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_LVT_LINT0: u64 = 0x350;
+const LAPIC_LVT_LINT1: u64 = 0x360;
+const LAPIC_LVT_ERROR: u64 = 0x370;
+const LAPIC_SIZE: u64 = 0x400;
+const LVT_MASKED: u32 = 1 << 16;
+const SVR_ENABLE: u32 = 1 << 8;
+
+// I/O APIC registers are reached by writing their index to IOREGSEL and accessing IOWIN
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IOAPIC_SIZE: u64 = 0x20;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+// Virtual address of the local APIC's registers, 0 while the PICs are in use
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+// Locking also serializes the select-and-access sequences of the I/O APIC registers
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]> = Mutex::new([None; MAX_IO_APICS]);
+static MADT: Mutex<Option<Madt>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub enum ApicError {
+    // The CPU has no local APIC
+    NotSupported,
+    // The firmware has no MADT, so the I/O APICs can't be found
+    NoMadt,
+    NoIoApic,
+    Map(IoRemapError),
+    // No I/O APIC handles the GSI an IRQ is connected to
+    NoRoute { irq: u8, gsi: u32 },
+    // `init` hasn't switched to the APICs
+    NotEnabled,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr(), register);
+        ptr::read_volatile((self.base + IOWIN).as_ptr())
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr(), register);
+        ptr::write_volatile((self.base + IOWIN).as_mut_ptr(), value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    unsafe fn redirection(&self, gsi: u32) -> u64 {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        u64::from(self.read(register)) | u64::from(self.read(register + 1)) << 32
+    }
+
+    unsafe fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        // mask the entry while it is half written
+        self.write(register, (entry as u32) | REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+// Whether `init` switched from the PICs to the APICs
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::SeqCst) != 0
+}
+
+/*
+Switches interrupt delivery from the legacy PICs to the local and I/O APICs
+
+Needs `memory::install`. On failure the PICs stay in charge and nothing is changed.
+ */
+pub fn init() -> Result<(), ApicError> {
+    let has_apic = unsafe { __cpuid(1) }.edx & (1 << 9) != 0;
+    if !has_apic {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = acpi::madt().ok_or(ApicError::NoMadt)?;
+    if madt.io_apics().next().is_none() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    // the mappings are unmapped again if one of them fails
+    let local_apic = map_registers(madt.local_apic, LAPIC_SIZE)?;
+    let mut mappings: [Option<IoMapping>; MAX_IO_APICS] = Default::default();
+    let mut io_apics = [None; MAX_IO_APICS];
+    for (i, info) in madt.io_apics().enumerate().take(MAX_IO_APICS) {
+        let mapping = map_registers(info.addr, IOAPIC_SIZE)?;
+        let mut io_apic = IoApic {
+            base: mapping.virt_addr(),
+            gsi_base: info.gsi_base,
+            entries: 0,
+        };
+        unsafe {
+            io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+            for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+                io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+            }
+        }
+        io_apics[i] = Some(io_apic);
+        mappings[i] = Some(mapping);
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        unsafe {
+            // the PICs stay initialized, so that a spurious interrupt of theirs still arrives at
+            // one of their vectors instead of an exception's. Machines without them have nothing
+            // at their ports.
+            if madt.has_pics {
+                set_pic_masks(0xff, 0xff);
+            }
+            enable_local_apic(&local_apic);
+        }
+        let destination = u64::from(local_apic.read::<u32>(LAPIC_ID) >> 24);
+        for isa_irq in (0..ISA_IRQS).filter_map(|irq| madt.isa_irq(irq)) {
+            let vector = PIC_1_OFFSET + isa_irq.irq;
+            let mut entry = u64::from(vector) | destination << 56 | REDIRECTION_MASKED;
+            if isa_irq.active_low {
+                entry |= REDIRECTION_ACTIVE_LOW;
+            }
+            if isa_irq.level_triggered {
+                entry |= REDIRECTION_LEVEL;
+            }
+            // IRQs without an I/O APIC input simply never arrive
+            if let Some(io_apic) = io_apics.iter().flatten().find(|a| a.handles(isa_irq.gsi)) {
+                unsafe { io_apic.set_redirection(isa_irq.gsi, entry) };
+            }
+        }
+        *IO_APICS.lock() = io_apics;
+        *MADT.lock() = Some(madt);
+        LOCAL_APIC.store(local_apic.virt_addr().as_u64(), Ordering::SeqCst);
+    });
+
+    // the APICs stay mapped for as long as the kernel runs
+    mem::forget(local_apic);
+    mem::forget(mappings);
+    Ok(())
+}
+
+// Masks or unmasks an ISA IRQ at the I/O APIC it is routed to
+pub fn set_irq_masked(irq: u8, masked: bool) -> Result<(), ApicError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let madt = (*MADT.lock()).ok_or(ApicError::NotEnabled)?;
+        let gsi = u32::from(irq);
+        let gsi = madt
+            .isa_irq(irq)
+            .ok_or(ApicError::NoRoute { irq, gsi })?
+            .gsi;
+        let io_apics = IO_APICS.lock();
+        let io_apic = io_apics
+            .iter()
+            .flatten()
+            .find(|a| a.handles(gsi))
+            .ok_or(ApicError::NoRoute { irq, gsi })?;
+        unsafe {
+            let entry = io_apic.redirection(gsi);
+            if masked {
+                io_apic.set_redirection(gsi, entry | REDIRECTION_MASKED);
+            } else {
+                io_apic.set_redirection(gsi, entry & !REDIRECTION_MASKED);
+            }
+        }
+        Ok(())
+    })
+}
+
+// Signals the end of the current interrupt to the local APIC
+pub fn end_of_interrupt() {
+    let base = LOCAL_APIC.load(Ordering::SeqCst);
+    assert_ne!(base, 0, "local APIC not enabled");
+    unsafe { ptr::write_volatile((base + LAPIC_EOI) as *mut u32, 0) };
+}
+
+// APIC ID of the current CPU, None while the PICs are in use
+pub fn local_apic_id() -> Option<u8> {
+    match LOCAL_APIC.load(Ordering::SeqCst) {
+        0 => None,
+        base => Some((unsafe { ptr::read_volatile((base + LAPIC_ID) as *const u32) } >> 24) as u8),
+    }
+}
+
+fn map_registers(addr: PhysAddr, size: u64) -> Result<IoMapping, ApicError> {
+    unsafe { mmio::ioremap(addr, size, CacheMode::Uncached) }.map_err(ApicError::Map)
+}
+
+unsafe fn enable_local_apic(local_apic: &IoMapping) {
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    let value = apic_base.read();
+    apic_base.write(value | APIC_BASE_ENABLE);
+
+    // the local interrupt pins carry the PICs' output in virtual wire mode, which isn't used
+    // anymore, and the local APIC timer isn't used yet
+    let lvts = [
+        LAPIC_LVT_TIMER,
+        LAPIC_LVT_LINT0,
+        LAPIC_LVT_LINT1,
+        LAPIC_LVT_ERROR,
+    ];
+    for &lvt in lvts.iter() {
+        local_apic.write(lvt, LVT_MASKED);
+    }
+    local_apic.write::<u32>(LAPIC_TPR, 0);
+    local_apic.write(LAPIC_SVR, SVR_ENABLE | u32::from(SPURIOUS_VECTOR));
+}
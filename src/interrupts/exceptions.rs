@@ -0,0 +1,566 @@
+// Handlers for the architectural exceptions.
+//
+// Every exception gets its own entry stub, so that a #GP or #UD is reported as what it is instead
+// of escalating to a double fault. The stubs save the interrupted code's registers in a
+// `TrapFrame` and `dispatch` collects everything known about the exception into an
+// `ExceptionInfo`: the decoded error code, the registers, the control registers and the bytes of
+// the faulting instruction. A hook installed with `set_hook` sees the exception first and may
+// resume execution, which is how tests check that an exception occurs. Otherwise breakpoints,
+// debug traps and NMIs are printed and execution continues, everything else panics with the
+// report.
+use super::trap::{ControlRegisters, TrapFrame};
+use crate::backtrace::{self, Backtrace};
+use crate::{gdt, memory, println};
+use core::{fmt, mem, ptr};
+use spin::Mutex;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+// Longest possible x86 instruction
+const MAX_INSTRUCTION_LEN: usize = 15;
+
+static HOOK: Mutex<Option<ExceptionHook>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    SecurityException = 30,
+}
+
+impl Exception {
+    pub fn vector(self) -> u8 {
+        self as u8
+    }
+
+    // Short name as used in the manuals, like "#GP"
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Exception::DivideError => "#DE",
+            Exception::Debug => "#DB",
+            Exception::NonMaskableInterrupt => "NMI",
+            Exception::Breakpoint => "#BP",
+            Exception::Overflow => "#OF",
+            Exception::BoundRangeExceeded => "#BR",
+            Exception::InvalidOpcode => "#UD",
+            Exception::DeviceNotAvailable => "#NM",
+            Exception::DoubleFault => "#DF",
+            Exception::InvalidTss => "#TS",
+            Exception::SegmentNotPresent => "#NP",
+            Exception::StackSegmentFault => "#SS",
+            Exception::GeneralProtectionFault => "#GP",
+            Exception::PageFault => "#PF",
+            Exception::X87FloatingPoint => "#MF",
+            Exception::AlignmentCheck => "#AC",
+            Exception::MachineCheck => "#MC",
+            Exception::SimdFloatingPoint => "#XM",
+            Exception::Virtualization => "#VE",
+            Exception::SecurityException => "#SX",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Exception::DivideError => "divide error",
+            Exception::Debug => "debug",
+            Exception::NonMaskableInterrupt => "non-maskable interrupt",
+            Exception::Breakpoint => "breakpoint",
+            Exception::Overflow => "overflow",
+            Exception::BoundRangeExceeded => "bound range exceeded",
+            Exception::InvalidOpcode => "invalid opcode",
+            Exception::DeviceNotAvailable => "device not available",
+            Exception::DoubleFault => "double fault",
+            Exception::InvalidTss => "invalid TSS",
+            Exception::SegmentNotPresent => "segment not present",
+            Exception::StackSegmentFault => "stack segment fault",
+            Exception::GeneralProtectionFault => "general protection fault",
+            Exception::PageFault => "page fault",
+            Exception::X87FloatingPoint => "x87 floating point exception",
+            Exception::AlignmentCheck => "alignment check",
+            Exception::MachineCheck => "machine check",
+            Exception::SimdFloatingPoint => "SIMD floating point exception",
+            Exception::Virtualization => "virtualization exception",
+            Exception::SecurityException => "security exception",
+        }
+    }
+
+    // Whether the kernel can't continue after the exception unless a hook dealt with it
+    pub fn is_fatal(self) -> bool {
+        !matches!(
+            self,
+            Exception::Debug | Exception::NonMaskableInterrupt | Exception::Breakpoint
+        )
+    }
+
+    // Double faults and machine checks, which can't be returned from
+    pub fn is_abort(self) -> bool {
+        matches!(self, Exception::DoubleFault | Exception::MachineCheck)
+    }
+
+    pub fn has_error_code(self) -> bool {
+        matches!(
+            self,
+            Exception::DoubleFault
+                | Exception::InvalidTss
+                | Exception::SegmentNotPresent
+                | Exception::StackSegmentFault
+                | Exception::GeneralProtectionFault
+                | Exception::PageFault
+                | Exception::AlignmentCheck
+                | Exception::SecurityException
+        )
+    }
+
+    fn from_vector(vector: u8) -> Option<Self> {
+        let exception = match vector {
+            0 => Exception::DivideError,
+            1 => Exception::Debug,
+            2 => Exception::NonMaskableInterrupt,
+            3 => Exception::Breakpoint,
+            4 => Exception::Overflow,
+            5 => Exception::BoundRangeExceeded,
+            6 => Exception::InvalidOpcode,
+            7 => Exception::DeviceNotAvailable,
+            8 => Exception::DoubleFault,
+            10 => Exception::InvalidTss,
+            11 => Exception::SegmentNotPresent,
+            12 => Exception::StackSegmentFault,
+            13 => Exception::GeneralProtectionFault,
+            14 => Exception::PageFault,
+            16 => Exception::X87FloatingPoint,
+            17 => Exception::AlignmentCheck,
+            18 => Exception::MachineCheck,
+            19 => Exception::SimdFloatingPoint,
+            20 => Exception::Virtualization,
+            30 => Exception::SecurityException,
+            _ => return None,
+        };
+        Some(exception)
+    }
+
+    fn decode_error_code(self, code: u64) -> ErrorCode {
+        match self {
+            // 0 if the exception wasn't caused by loading a selector
+            Exception::InvalidTss
+            | Exception::SegmentNotPresent
+            | Exception::StackSegmentFault
+            | Exception::GeneralProtectionFault
+                if code != 0 =>
+            {
+                ErrorCode::Selector(SelectorErrorCode(code))
+            }
+            Exception::PageFault => {
+                ErrorCode::PageFault(PageFaultErrorCode::from_bits_truncate(code))
+            }
+            _ => ErrorCode::Other(code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+// Error code naming the segment selector or IDT vector an exception was caused by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+    // Whether the exception happened while delivering an external event like an interrupt
+    pub fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(self) -> DescriptorTable {
+        // bit 1 selects the IDT, otherwise bit 2 chooses between the GDT and the LDT
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    // Index into the table, the vector for the IDT
+    pub fn index(self) -> u16 {
+        ((self.0 >> 3) & 0x1fff) as u16
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let table = match self.table() {
+            DescriptorTable::Gdt => "GDT",
+            DescriptorTable::Idt => "IDT",
+            DescriptorTable::Ldt => "LDT",
+        };
+        write!(f, "{}[{}]", table, self.index())?;
+        if self.external() {
+            write!(f, ", external event")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Selector(SelectorErrorCode),
+    PageFault(PageFaultErrorCode),
+    Other(u64),
+}
+
+impl ErrorCode {
+    pub fn bits(self) -> u64 {
+        match self {
+            ErrorCode::Selector(selector) => selector.0,
+            ErrorCode::PageFault(code) => code.bits(),
+            ErrorCode::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.bits())?;
+        match self {
+            ErrorCode::Selector(selector) => write!(f, " ({})", selector),
+            ErrorCode::PageFault(code) => write!(f, " ({:?})", code),
+            ErrorCode::Other(_) => Ok(()),
+        }
+    }
+}
+
+// Everything known about an exception when its handler runs
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionInfo {
+    pub exception: Exception,
+    // None for exceptions that don't push one
+    pub error_code: Option<ErrorCode>,
+    // The interrupted code's registers. Use the `HookAction` to change where it resumes.
+    pub frame: TrapFrame,
+    pub control: ControlRegisters,
+    // The address whose access caused a page fault (CR2)
+    pub fault_address: Option<VirtAddr>,
+    instruction: [u8; MAX_INSTRUCTION_LEN],
+    instruction_len: usize,
+}
+
+impl ExceptionInfo {
+    fn new(exception: Exception, frame: &TrapFrame) -> Self {
+        let (instruction, instruction_len) = read_instruction(frame.rip);
+        let control = ControlRegisters::read();
+        let fault_address = match exception {
+            Exception::PageFault => Some(Cr2::read()),
+            _ => None,
+        };
+        let error_code = match exception.has_error_code() {
+            true => Some(exception.decode_error_code(frame.error_code)),
+            false => None,
+        };
+        ExceptionInfo {
+            exception,
+            error_code,
+            frame: *frame,
+            control,
+            fault_address,
+            instruction,
+            instruction_len,
+        }
+    }
+
+    // Up to 15 bytes starting at the instruction pointer, fewer if they run into unmapped memory
+    pub fn instruction_bytes(&self) -> &[u8] {
+        &self.instruction[..self.instruction_len]
+    }
+}
+
+impl fmt::Display for ExceptionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.exception.mnemonic(), self.exception.name())?;
+        if let Some(error_code) = self.error_code {
+            write!(f, ", error code {}", error_code)?;
+        }
+        if let Some(addr) = self.fault_address {
+            write!(f, "\naccessed address {:#x}", addr.as_u64())?;
+        }
+        write!(f, "\n{}{}\ncode at RIP:", self.frame, self.control)?;
+        if self.instruction_len == 0 {
+            write!(f, " not mapped")?;
+        }
+        for byte in self.instruction_bytes() {
+            write!(f, " {:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+// What happens after a hook has seen an exception
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+    // Report the exception as if there were no hook
+    Report,
+    // Return to the instruction pointer, e.g. after mapping the page a fault was caused by
+    Retry,
+    // Resume the given number of bytes behind the instruction pointer, skipping the faulting
+    // instruction
+    Skip(u64),
+}
+
+pub type ExceptionHook = fn(&ExceptionInfo) -> HookAction;
+
+/*
+Makes `hook` see every exception before it is reported, returning the previous hook
+
+The hook runs inside the exception handler, so it must not take locks that might be held by the
+interrupted code. Double faults and machine checks can't be resumed from, for them the action is
+ignored.
+ */
+pub fn set_hook(hook: Option<ExceptionHook>) -> Option<ExceptionHook> {
+    x86_64::instructions::interrupts::without_interrupts(|| mem::replace(&mut *HOOK.lock(), hook))
+}
+
+// Entry stub for an exception. The CPU only pushes an error code for some exceptions, the others
+// push a 0 in its place so that all of them share the `TrapFrame` layout.
+macro_rules! exception_stub {
+    ($name:ident, $exception:expr) => {
+        #[naked]
+        unsafe extern "C" fn $name() -> ! {
+            asm!(
+                "push 0",
+                "push {vector}",
+                "jmp {entry}",
+                vector = const $exception as u8,
+                entry = sym exception_entry,
+                options(noreturn)
+            );
+        }
+    };
+    ($name:ident, $exception:expr, error_code) => {
+        #[naked]
+        unsafe extern "C" fn $name() -> ! {
+            asm!(
+                "push {vector}",
+                "jmp {entry}",
+                vector = const $exception as u8,
+                entry = sym exception_entry,
+                options(noreturn)
+            );
+        }
+    };
+}
+
+exception_stub!(divide_error_stub, Exception::DivideError);
+exception_stub!(debug_stub, Exception::Debug);
+exception_stub!(nmi_stub, Exception::NonMaskableInterrupt);
+exception_stub!(breakpoint_stub, Exception::Breakpoint);
+exception_stub!(overflow_stub, Exception::Overflow);
+exception_stub!(bound_range_stub, Exception::BoundRangeExceeded);
+exception_stub!(invalid_opcode_stub, Exception::InvalidOpcode);
+exception_stub!(device_not_available_stub, Exception::DeviceNotAvailable);
+exception_stub!(double_fault_stub, Exception::DoubleFault, error_code);
+exception_stub!(invalid_tss_stub, Exception::InvalidTss, error_code);
+exception_stub!(
+    segment_not_present_stub,
+    Exception::SegmentNotPresent,
+    error_code
+);
+exception_stub!(stack_segment_stub, Exception::StackSegmentFault, error_code);
+exception_stub!(
+    general_protection_stub,
+    Exception::GeneralProtectionFault,
+    error_code
+);
+exception_stub!(page_fault_stub, Exception::PageFault, error_code);
+exception_stub!(x87_stub, Exception::X87FloatingPoint);
+exception_stub!(alignment_check_stub, Exception::AlignmentCheck, error_code);
+exception_stub!(machine_check_stub, Exception::MachineCheck);
+exception_stub!(simd_stub, Exception::SimdFloatingPoint);
+exception_stub!(virtualization_stub, Exception::Virtualization);
+exception_stub!(security_stub, Exception::SecurityException, error_code);
+
+/*
+Saves the general purpose registers below the vector and error code the stub pushed, completing the
+`TrapFrame`, and passes it to `dispatch`
+
+The CPU aligns the stack to 16 bytes before pushing its frame, and the 22 words of the `TrapFrame`
+keep it aligned for the call.
+ */
+#[naked]
+unsafe extern "C" fn exception_entry() -> ! {
+    asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "cld",
+        "call {dispatch}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        // the vector and error code
+        "add rsp, 16",
+        "iretq",
+        dispatch = sym dispatch,
+        options(noreturn)
+    );
+}
+
+// Points an IDT entry to an entry stub. The entries only take handler functions of the
+// `x86-interrupt` ABI, whose type merely carries the address here.
+macro_rules! set_stub {
+    ($entry:expr, $stub:ident) => {
+        $entry.set_handler_fn(mem::transmute($stub as usize))
+    };
+}
+
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        set_stub!(idt.divide_error, divide_error_stub);
+        set_stub!(idt.debug, debug_stub);
+        set_stub!(idt.non_maskable_interrupt, nmi_stub);
+        set_stub!(idt.breakpoint, breakpoint_stub);
+        set_stub!(idt.overflow, overflow_stub);
+        set_stub!(idt.bound_range_exceeded, bound_range_stub);
+        set_stub!(idt.invalid_opcode, invalid_opcode_stub);
+        set_stub!(idt.device_not_available, device_not_available_stub);
+        set_stub!(idt.double_fault, double_fault_stub).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        set_stub!(idt.invalid_tss, invalid_tss_stub);
+        set_stub!(idt.segment_not_present, segment_not_present_stub);
+        set_stub!(idt.stack_segment_fault, stack_segment_stub);
+        set_stub!(idt.general_protection_fault, general_protection_stub);
+        set_stub!(idt.page_fault, page_fault_stub);
+        set_stub!(idt.x87_floating_point, x87_stub);
+        set_stub!(idt.alignment_check, alignment_check_stub);
+        set_stub!(idt.machine_check, machine_check_stub);
+        set_stub!(idt.simd_floating_point, simd_stub);
+        set_stub!(idt.virtualization, virtualization_stub);
+        set_stub!(idt.security_exception, security_stub);
+    }
+}
+
+extern "C" fn dispatch(frame: &mut TrapFrame) {
+    let exception = match Exception::from_vector(frame.vector as u8) {
+        Some(exception) => exception,
+        None => panic!("exception stub for unknown vector {}", frame.vector),
+    };
+
+    // faults in demand paged areas and writes to copy-on-write pages are expected, the access is
+    // retried once the page is mapped
+    if exception == Exception::PageFault {
+        let addr = Cr2::read();
+        let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+        if memory::demand::handle_page_fault(addr, error_code)
+            || memory::cow::handle_page_fault(addr, error_code)
+        {
+            return;
+        }
+    }
+
+    let info = ExceptionInfo::new(exception, frame);
+    let action = run_hook(&info);
+    // the panic handler prints the interrupted code's backtrace instead of its own, which would
+    // start in this handler
+    let interrupted = Backtrace::from_registers(frame.rip, frame.rbp);
+    if exception.is_abort() {
+        backtrace::set_panic_backtrace(interrupted);
+        panic!("EXCEPTION: {}", info);
+    }
+    match action {
+        HookAction::Report => {}
+        HookAction::Retry => return,
+        HookAction::Skip(len) => {
+            frame.rip += len;
+            return;
+        }
+    }
+    if exception.is_fatal() {
+        backtrace::set_panic_backtrace(interrupted);
+        panic!("EXCEPTION: {}", info);
+    }
+    println!("EXCEPTION: {}", info);
+}
+
+fn run_hook(info: &ExceptionInfo) -> HookAction {
+    // the interrupted code might be in `set_hook`
+    match HOOK.try_lock().and_then(|hook| *hook) {
+        Some(hook) => hook(info),
+        None => HookAction::Report,
+    }
+}
+
+// Copies the bytes at `rip`, stopping at the first one that isn't mapped
+fn read_instruction(rip: u64) -> ([u8; MAX_INSTRUCTION_LEN], usize) {
+    let mut bytes = [0; MAX_INSTRUCTION_LEN];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let addr = match VirtAddr::try_new(rip.wrapping_add(i as u64)) {
+            Ok(addr) => addr,
+            Err(_) => return (bytes, i),
+        };
+        // each page only needs to be checked once
+        if (i == 0 || addr.is_aligned(4096u64)) && !memory::is_mapped(addr) {
+            return (bytes, i);
+        }
+        *byte = unsafe { ptr::read_volatile(addr.as_ptr::<u8>()) };
+    }
+    (bytes, MAX_INSTRUCTION_LEN)
+}
+
+#[test_case]
+fn test_selector_error_code() {
+    let code = SelectorErrorCode(0x100 << 3);
+    assert_eq!(code.table(), DescriptorTable::Gdt);
+    assert_eq!(code.index(), 0x100);
+    assert!(!code.external());
+
+    // IDT vector 13 while delivering an external interrupt
+    let code = SelectorErrorCode(13 << 3 | 0b011);
+    assert_eq!(code.table(), DescriptorTable::Idt);
+    assert_eq!(code.index(), 13);
+    assert!(code.external());
+    assert_eq!(SelectorErrorCode(0b100).table(), DescriptorTable::Ldt);
+}
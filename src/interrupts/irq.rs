@@ -0,0 +1,219 @@
+// Hooking hardware interrupts without touching the IDT.
+//
+// Every vector from `IRQ_BASE` up gets a generated stub that passes its number on to `dispatch`,
+// which calls the handlers registered for the IRQ (the vector minus `IRQ_BASE`) in the order they
+// were registered and then signals the end of the interrupt to whichever controller is in charge.
+// The ISA IRQs 0 to 15 are unmasked while they have a handler, at the PICs or the I/O APIC.
+use super::{apic, controller, end_of_interrupt, pic_masks, set_pic_masks};
+use super::{InterruptController, PIC_1_OFFSET};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+pub const IRQ_BASE: u8 = PIC_1_OFFSET;
+// The last vector is left to the local APIC's spurious interrupts
+pub const IRQ_COUNT: usize = (apic::SPURIOUS_VECTOR - IRQ_BASE) as usize;
+const MAX_SHARED_HANDLERS: usize = 4;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_READ_ISR: u8 = 0x0b;
+const PIC_EOI: u8 = 0x20;
+
+pub type IrqHandler = fn(&InterruptContext);
+
+static HANDLERS: Mutex<[[Option<IrqHandler>; MAX_SHARED_HANDLERS]; IRQ_COUNT]> =
+    Mutex::new([[None; MAX_SHARED_HANDLERS]; IRQ_COUNT]);
+
+// What a handler gets to know about the interrupt it is called for
+#[derive(Debug)]
+pub struct InterruptContext<'a> {
+    pub irq: u8,
+    pub vector: u8,
+    pub stack_frame: &'a InterruptStackFrame,
+}
+
+#[derive(Debug)]
+pub enum IrqError {
+    // No vector belongs to the IRQ
+    InvalidIrq(u8),
+    // The IRQ already has `MAX_SHARED_HANDLERS` handlers
+    TooManyHandlers,
+    AlreadyRegistered,
+    NotRegistered,
+    // Only the ISA IRQs have a line that can be masked
+    NotMaskable(u8),
+    Apic(apic::ApicError),
+}
+
+// Points every vector from `IRQ_BASE` on to a stub calling `dispatch`
+macro_rules! set_irq_stubs {
+    ($idt:expr; $($row:expr),*) => {
+        $(set_irq_stubs!(@row $idt, $row; 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);)*
+    };
+    (@row $idt:expr, $row:expr; $($column:expr),*) => {
+        $({
+            extern "x86-interrupt" fn stub(stack_frame: &mut InterruptStackFrame) {
+                dispatch($row + $column, stack_frame);
+            }
+            $idt[$row + $column].set_handler_fn(stub);
+        })*
+    };
+}
+
+pub(super) fn set_stubs(idt: &mut InterruptDescriptorTable) {
+    set_irq_stubs!(
+        idt;
+        0x20, 0x30, 0x40, 0x50, 0x60, 0x70, 0x80, 0x90, 0xa0, 0xb0, 0xc0, 0xd0, 0xe0, 0xf0
+    );
+}
+
+/*
+Calls `handler` whenever the IRQ arrives, after the handlers registered before it
+
+The IRQ is unmasked when it gets its first handler. Handlers run with interrupts disabled and must
+not take locks that are held while interrupts are enabled.
+ */
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    check_irq(irq)?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let slots = &mut handlers[usize::from(irq)];
+        if slots
+            .iter()
+            .flatten()
+            .any(|&h| h as usize == handler as usize)
+        {
+            return Err(IrqError::AlreadyRegistered);
+        }
+        let index = slots
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or(IrqError::TooManyHandlers)?;
+        slots[index] = Some(handler);
+
+        if index == 0 && irq < apic::ISA_IRQS {
+            if let Err(err) = set_masked(irq, false) {
+                slots[index] = None;
+                return Err(err);
+            }
+        }
+        Ok(())
+    })
+}
+
+// Removes a handler added with `register_irq`, masking the IRQ if it was the last one
+pub fn unregister_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    check_irq(irq)?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let slots = &mut handlers[usize::from(irq)];
+        let index = slots
+            .iter()
+            .position(|slot| slot.map_or(false, |h| h as usize == handler as usize))
+            .ok_or(IrqError::NotRegistered)?;
+        // keep the remaining handlers in order
+        slots[index] = None;
+        slots[index..].rotate_left(1);
+
+        if slots[0].is_none() && irq < apic::ISA_IRQS {
+            set_masked(irq, true)?;
+        }
+        Ok(())
+    })
+}
+
+// Stops an ISA IRQ from arriving until `unmask_irq`, e.g. while its device is reprogrammed
+pub fn mask_irq(irq: u8) -> Result<(), IrqError> {
+    set_masked(irq, true)
+}
+
+pub fn unmask_irq(irq: u8) -> Result<(), IrqError> {
+    set_masked(irq, false)
+}
+
+// Unmasks every ISA IRQ that has a handler at the current controller, after switching controllers
+pub(super) fn unmask_registered() -> Result<(), IrqError> {
+    for irq in 0..apic::ISA_IRQS {
+        let registered = x86_64::instructions::interrupts::without_interrupts(|| {
+            HANDLERS.lock()[usize::from(irq)][0].is_some()
+        });
+        if registered {
+            set_masked(irq, false)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_irq(irq: u8) -> Result<(), IrqError> {
+    if usize::from(irq) < IRQ_COUNT {
+        Ok(())
+    } else {
+        Err(IrqError::InvalidIrq(irq))
+    }
+}
+
+fn set_masked(irq: u8, masked: bool) -> Result<(), IrqError> {
+    if irq >= apic::ISA_IRQS {
+        return Err(IrqError::NotMaskable(irq));
+    }
+    match controller() {
+        InterruptController::Apic => apic::set_irq_masked(irq, masked).map_err(IrqError::Apic),
+        InterruptController::Pic => {
+            x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+                let (primary, secondary) = pic_masks();
+                let masks = u16::from(primary) | u16::from(secondary) << 8;
+                let masks = if masked {
+                    masks | 1 << irq
+                } else {
+                    masks & !(1 << irq)
+                };
+                set_pic_masks(masks as u8, (masks >> 8) as u8);
+            });
+            Ok(())
+        }
+    }
+}
+
+fn dispatch(vector: u8, stack_frame: &InterruptStackFrame) {
+    let irq = vector - IRQ_BASE;
+    if usize::from(irq) >= IRQ_COUNT
+        || (controller() == InterruptController::Pic && is_spurious_pic_irq(irq))
+    {
+        return;
+    }
+    // copied, so that handlers may register and unregister handlers themselves
+    let handlers = HANDLERS.lock()[usize::from(irq)];
+    let context = InterruptContext {
+        irq,
+        vector,
+        stack_frame,
+    };
+    for handler in handlers.iter().flatten() {
+        handler(&context);
+    }
+    end_of_interrupt(vector);
+}
+
+// The PICs raise IRQ 7 or 15 for an interrupt that went away before the CPU acknowledged it, which
+// shows in the IRQ not being in service. Those must not be acknowledged, except at the primary PIC
+// for the cascade of a spurious IRQ 15.
+fn is_spurious_pic_irq(irq: u8) -> bool {
+    let command = match irq {
+        7 => PIC_1_COMMAND,
+        15 => PIC_2_COMMAND,
+        _ => return false,
+    };
+    let mut command = Port::<u8>::new(command);
+    let in_service = unsafe {
+        command.write(PIC_READ_ISR);
+        command.read()
+    };
+    if in_service & 0x80 != 0 {
+        return false;
+    }
+    if irq == 15 {
+        unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) };
+    }
+    true
+}
@@ -0,0 +1,155 @@
+// The state of the interrupted code as saved by the exception entry stubs.
+//
+// The stubs in `exceptions` push the vector, an error code (0 for exceptions without one) and all
+// general purpose registers below the frame the CPU pushed, so that the whole `TrapFrame` lies on
+// the stack in this layout. Changes the handler makes to it are restored on return.
+use core::fmt;
+use x86_64::registers::model_specific::Msr;
+
+const IA32_EFER: u32 = 0xc000_0080;
+
+// RFLAGS bits and the names they are printed with
+const RFLAGS_BITS: [(u64, &str); 16] = [
+    (1 << 0, "CF"),
+    (1 << 2, "PF"),
+    (1 << 4, "AF"),
+    (1 << 6, "ZF"),
+    (1 << 7, "SF"),
+    (1 << 8, "TF"),
+    (1 << 9, "IF"),
+    (1 << 10, "DF"),
+    (1 << 11, "OF"),
+    (1 << 14, "NT"),
+    (1 << 16, "RF"),
+    (1 << 17, "VM"),
+    (1 << 18, "AC"),
+    (1 << 19, "VIF"),
+    (1 << 20, "VIP"),
+    (1 << 21, "ID"),
+];
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    // pushed by the CPU. For faults `rip` points to the faulting instruction, for traps like #BP to
+    // the one after it.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "RIP {:#018x}  CS {:#x}  RFLAGS {:#x} [{}]",
+            self.rip,
+            self.cs,
+            self.rflags,
+            Rflags(self.rflags)
+        )?;
+        writeln!(f, "RSP {:#018x}  SS {:#x}", self.rsp, self.ss)?;
+        let registers = [
+            ("RAX", self.rax),
+            ("RBX", self.rbx),
+            ("RCX", self.rcx),
+            ("RDX", self.rdx),
+            ("RSI", self.rsi),
+            ("RDI", self.rdi),
+            ("RBP", self.rbp),
+            ("R8 ", self.r8),
+            ("R9 ", self.r9),
+            ("R10", self.r10),
+            ("R11", self.r11),
+            ("R12", self.r12),
+            ("R13", self.r13),
+            ("R14", self.r14),
+            ("R15", self.r15),
+        ];
+        for (i, (name, value)) in registers.iter().enumerate() {
+            let separator = if i % 3 == 2 { "\n" } else { "  " };
+            write!(f, "{} {:#018x}{}", name, value, separator)?;
+        }
+        Ok(())
+    }
+}
+
+// Set RFLAGS bits by name, followed by the I/O privilege level
+pub struct Rflags(pub u64);
+
+impl fmt::Display for Rflags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(bit, name) in RFLAGS_BITS.iter() {
+            if self.0 & bit != 0 {
+                write!(f, "{} ", name)?;
+            }
+        }
+        write!(f, "IOPL={}", (self.0 >> 12) & 0b11)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ControlRegisters {
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+}
+
+impl ControlRegisters {
+    pub fn read() -> Self {
+        let (cr0, cr2, cr3, cr4): (u64, u64, u64, u64);
+        unsafe {
+            asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        }
+        ControlRegisters {
+            cr0,
+            cr2,
+            cr3,
+            cr4,
+            efer: unsafe { Msr::new(IA32_EFER).read() },
+        }
+    }
+}
+
+impl fmt::Display for ControlRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "CR0 {:#018x}  CR2 {:#018x}  CR3 {:#018x}",
+            self.cr0, self.cr2, self.cr3
+        )?;
+        write!(f, "CR4 {:#018x}  EFER {:#x}", self.cr4, self.efer)
+    }
+}
+
+#[test_case]
+fn test_rflags_names() {
+    use alloc::format;
+
+    assert_eq!(format!("{}", Rflags(0x246)), "PF ZF IF IOPL=0");
+    assert_eq!(format!("{}", Rflags(0x3002)), "IOPL=3");
+}
@@ -6,11 +6,15 @@
 #![reexport_test_harness_main = "test_main"]
 #![feature(alloc_error_handler)]
 #![feature(const_mut_refs)]
+#![feature(asm)]
+#![feature(naked_functions)]
 
 extern crate alloc;
 
 use core::panic::PanicInfo;
 
+pub mod acpi;
+pub mod backtrace;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
@@ -21,9 +25,8 @@ pub mod allocator;
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
-    unsafe {
-        interrupts::PICS.lock().initialize();
-    }
+    memory::mmio::init_pat();
+    interrupts::init_pics();
     x86_64::instructions::interrupts::enable();
 }
 pub trait Testable {
@@ -52,6 +55,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_println!("{}", backtrace::panic_backtrace());
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -22,7 +22,7 @@ entry_point!(kernel_main);
 // Entry point, since the linker looks for a function named `_start` by default
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use blog_os::allocator;
-    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use blog_os::memory::{self, buddy::BuddyFrameAllocator};
     use x86_64::{structures::paging::Page, VirtAddr};
 
     println!("Hello World{}", "!");
@@ -32,8 +32,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     // initialize a mapper
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::physmap::init(&boot_info.memory_map);
+    memory::physmap::print_report(&boot_info.memory_map);
     let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
 
     // map unused page
@@ -45,6 +47,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     unsafe { page_ptr.offset(400).write_volatile(0x_f021_f077_f065_f04e) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    blog_os::gdt::init_ist_stacks();
+    let controller = blog_os::interrupts::init_apic();
+    println!("interrupts delivered by {:?}", controller);
 
     let heap_value = Box::new(41);
     println!("heap_value at {:p}",&heap_value);
@@ -72,7 +78,12 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    use blog_os::{backtrace, serial_println};
+
+    let backtrace = backtrace::panic_backtrace();
+    println!("{}\n{}", info, backtrace);
+    // on the serial port as well, where it can be symbolized on the host
+    serial_println!("{}\n{}", info, backtrace);
     blog_os::hlt_loop();
 }
 
@@ -1,14 +1,288 @@
+use self::buddy::BuddyFrameAllocator;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use self::frames::{FrameFlags, PageTableFrames};
 use x86_64::{
+    instructions::tlb,
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        page::PageRangeInclusive, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageSize, PageTable, PageTableEntry, PageTableFlags, PhysFrame, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+pub mod address_space;
+pub mod buddy;
+pub mod cow;
+pub mod demand;
+pub mod frames;
+pub mod huge;
+pub mod mmio;
+pub mod physmap;
+pub mod protect;
+pub mod stack;
+pub mod vma;
+pub mod walk;
+
+pub use self::walk::{dump_page_tables, translate};
+
+// The kernel's page table mapper and frame allocator, for code like the heap that can't be
+// handed them as arguments
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BuddyFrameAllocator,
+}
+
+static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+// Virtual address at which the bootloader mapped the complete physical memory
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// Bumped whenever a mapping is removed or loses permissions. Address spaces compare it with the
+// value at their last activation to decide whether TLB entries tagged with their PCID can be kept.
+static TLB_GENERATION: AtomicU64 = AtomicU64::new(0);
+
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
     let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+    vma::KERNEL_VMAS
+        .lock()
+        .reserve_used_entries(level_4_table)
+        .expect("failed to reserve the bootloader's mappings");
+    let mut mapper = OffsetPageTable::new(level_4_table, physical_memory_offset);
+
+    // W^X for the kernel image, from here on the kernel can't write to its code either
+    protect::enable_no_execute();
+    protect::protect_kernel_image(&mut mapper).expect("failed to remap the kernel image");
+    protect::enable_write_protect();
+    mapper
+}
+
+// Hands the mapper and frame allocator set up at boot over to the rest of the kernel
+pub fn install(mut mapper: OffsetPageTable<'static>, mut frame_allocator: BuddyFrameAllocator) {
+    address_space::prepare_kernel_space(&mut mapper, &mut frame_allocator);
+    *KERNEL_MEMORY.lock() = Some(KernelMemory {
+        mapper,
+        frame_allocator,
+    });
+}
+
+/*
+Runs `f` with exclusive access to the kernel mapper and frame allocator
+
+Returns None if `install` hasn't been called yet. Interrupts are disabled while `f` runs, and since
+the heap grows through this function as well, `f` must not allocate on the heap.
+ */
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        KERNEL_MEMORY.lock().as_mut().map(f)
+    })
+}
+
+// Like `with_kernel_memory`, but returns None instead of spinning if the kernel memory is locked.
+// Meant for exception handlers, which may have interrupted the lock holder.
+pub(crate) fn try_with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        KERNEL_MEMORY.try_lock()?.as_mut().map(f)
+    })
+}
+
+// Makes address spaces flush the TLB entries tagged with their PCID on their next activation. Needed
+// after removing a mapping or taking permissions away, since `tlb::flush` only reaches the entries
+// of the active PCID.
+pub(crate) fn invalidate_tagged_tlb_entries() {
+    TLB_GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+pub(crate) fn tlb_generation() -> u64 {
+    TLB_GENERATION.load(Ordering::SeqCst)
+}
+
+// Returns the virtual address through which the given physical address can be accessed
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst) + addr.as_u64())
+}
+
+// Whether `addr` can be read without faulting. Always false before `init`, since the page tables
+// can't be walked without the physical memory mapping.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst) != 0 && translate(addr).phys.is_some()
+}
+
+/*
+Returns the level 1 entry mapping `addr` in the active page table
+
+Returns None if a table on the way is missing or `addr` lies in a huge page. Unsafe because the
+caller must make sure that nobody else modifies the entry at the same time.
+ */
+pub(crate) unsafe fn leaf_entry(addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut table: &mut PageTable =
+        &mut *phys_to_virt(level_4_table_frame.start_address()).as_mut_ptr();
+    for &index in [addr.p4_index(), addr.p3_index(), addr.p2_index()].iter() {
+        let flags = table[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = &mut *phys_to_virt(table[index].addr()).as_mut_ptr();
+    }
+    Some(&mut table[addr.p1_index()])
+}
+
+// What `unmap_range` removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnmapStats {
+    pub pages: usize,
+    // Frames given back to the frame allocator, shared or pinned frames are kept
+    pub frames_freed: usize,
+    // Page tables that became empty and were freed
+    pub tables_freed: usize,
+}
+
+#[derive(Debug)]
+pub enum UnmapRangeError {
+    // The range only covers part of a huge page, which can't be split
+    PartialHugePage(VirtAddr),
+}
+
+/*
+Unmaps every mapped page of the range and frees the P1, P2 and P3 tables that are empty afterwards
+
+Frames of the unmapped pages lose a reference in the page-frame database and are given back to
+`frame_deallocator` once nothing else maps them. Pages of the range that aren't mapped are skipped.
+Huge pages are unmapped as a whole, so they must lie completely inside the range.
+
+Unsafe because the caller must guarantee that nothing uses the range anymore, that the frames and
+page tables were taken from `frame_deallocator`, and that `mapper` is the active page table (the
+TLB is only flushed on the current CPU).
+ */
+pub unsafe fn unmap_range(
+    mapper: &mut OffsetPageTable,
+    pages: PageRangeInclusive,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> Result<UnmapStats, UnmapRangeError> {
+    unmap_pages(mapper, pages, true, frame_deallocator)
+}
+
+/*
+Like `unmap_range`, but leaves the frames of the unmapped pages alone, for mappings of memory the
+kernel doesn't own such as device registers. Empty page tables are still freed.
+
+Unsafe for the same reasons as `unmap_range`.
+ */
+pub unsafe fn unmap_io_range(
+    mapper: &mut OffsetPageTable,
+    pages: PageRangeInclusive,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> Result<UnmapStats, UnmapRangeError> {
+    unmap_pages(mapper, pages, false, frame_deallocator)
+}
+
+// Same as `unmap_range`, using the kernel mapper and frame allocator
+pub unsafe fn unmap_kernel_range(
+    pages: PageRangeInclusive,
+) -> Option<Result<UnmapStats, UnmapRangeError>> {
+    with_kernel_memory(|kernel| unmap_range(&mut kernel.mapper, pages, &mut kernel.frame_allocator))
+}
+
+unsafe fn unmap_pages(
+    mapper: &mut OffsetPageTable,
+    pages: PageRangeInclusive,
+    release_frames: bool,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> Result<UnmapStats, UnmapRangeError> {
+    if pages.is_empty() {
+        return Ok(UnmapStats::default());
+    }
+    let first = pages.start.start_address().as_u64();
+    let last = pages.end.start_address().as_u64() + (Size4KiB::SIZE - 1);
+    let mut stats = UnmapStats::default();
+    let level_4_table = mapper.level_4_table();
+    let range = first..=last;
+    invalidate_tagged_tlb_entries();
+    unmap_table(level_4_table, 4, 0, &range, release_frames, frame_deallocator, &mut stats)?;
+    Ok(stats)
+}
+
+// Unmaps the part of `range` covered by a table of the given level, whose first entry maps `base`
+unsafe fn unmap_table(
+    table: &mut PageTable,
+    level: u32,
+    base: u64,
+    range: &core::ops::RangeInclusive<u64>,
+    release_frames: bool,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    stats: &mut UnmapStats,
+) -> Result<(), UnmapRangeError> {
+    let entry_size = 1u64 << (12 + 9 * (level - 1));
+    for (index, entry) in table.iter_mut().enumerate() {
+        let entry_start = if level == 4 {
+            vma::p4_entry_start(index)
+        } else {
+            base + index as u64 * entry_size
+        };
+        let entry_last = entry_start + (entry_size - 1);
+        if entry_last < *range.start() || entry_start > *range.end() {
+            continue;
+        }
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        let frame = PhysFrame::containing_address(entry.addr());
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            if entry_start < *range.start() || entry_last > *range.end() {
+                return Err(UnmapRangeError::PartialHugePage(VirtAddr::new(entry_start)));
+            }
+            entry.set_unused();
+            tlb::flush(VirtAddr::new(entry_start));
+            stats.pages += 1;
+
+            if !release_frames {
+                continue;
+            }
+            // huge pages are backed by a block of 4 KiB frames that are freed one by one
+            let frame_count = entry_size / Size4KiB::SIZE;
+            for frame in PhysFrame::range(frame, frame + frame_count) {
+                if is_releasable(frame) && frames::put(frame) {
+                    frame_deallocator.deallocate_frame(frame);
+                    stats.frames_freed += 1;
+                }
+            }
+        } else {
+            let next: &mut PageTable = &mut *phys_to_virt(frame.start_address()).as_mut_ptr();
+            unmap_table(
+                next,
+                level - 1,
+                entry_start,
+                range,
+                release_frames,
+                frame_deallocator,
+                stats,
+            )?;
+
+            // the tables the bootloader set up don't belong to the frame allocator, and the P3
+            // tables of kernel space are shared by every address space
+            let shared = level == 4 && index >= 256;
+            if !shared && next.iter().all(|e| e.is_unused()) && is_releasable(frame) {
+                entry.set_unused();
+                address_space::flush_tlb();
+                frame_deallocator.deallocate_frame(frame);
+                stats.tables_freed += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether the frame came from the frame allocator, as opposed to MMIO, pinned boot memory or
+// free memory mapped directly by physical address
+fn is_releasable(frame: PhysFrame) -> bool {
+    frames::descriptor(frame)
+        .map_or(false, |d| !d.is_free() && !d.flags().contains(FrameFlags::PINNED))
 }
 
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
@@ -33,7 +307,8 @@ pub fn create_example_mapping(
     let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
     let flags = Flags::PRESENT | Flags::WRITABLE;
     // FIXME: UNSAFE, only for testing.
-    let map_to_result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
+    let map_to_result =
+        unsafe { mapper.map_to(page, frame, flags, &mut PageTableFrames(frame_allocator)) };
 
     map_to_result.expect("map_to failed").flush();
 }
@@ -69,8 +344,10 @@ impl BootInfoFrameAllocator {
 		//map each region to its address range
         let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
 
-		// transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+		// transform to an iterator of frame start addresses, leaving out reserved ranges
+        let frame_addresses = addr_ranges
+            .flat_map(|r| r.step_by(4096))
+            .filter(|&addr| physmap::reservation(PhysAddr::new(addr)).is_none());
 
 		//create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
@@ -0,0 +1,314 @@
+// Address spaces for isolated processes.
+//
+// Every address space has a P4 table of its own whose lower half belongs to the process, apart from
+// the P4 entries the kernel itself uses there: the bootloader links the kernel low and maps its
+// stack, the boot information and the physical memory in the lower half as well. Those entries are
+// copied from the kernel's P4 table together with the upper half and point to the kernel's own P3
+// tables, so the kernel looks the same in every address space. `prepare_kernel_space` gives every
+// upper-half entry a P3 table at boot, which makes kernel mappings created later show up in every
+// address space too.
+//
+// With PCIDs the TLB entries of an address space are tagged with its PCID and survive switching to
+// another one. They are only flushed on activation if a different address space used the PCID in
+// between or a mapping was removed anywhere since (see `memory::invalidate_tagged_tlb_entries`).
+use super::frames::{self, FrameFlags, PageTableFrames};
+use super::vma::{
+    VirtualMemoryManager, Vma, VmaError, VmaKind, KERNEL_SPACE_END, KERNEL_SPACE_START,
+};
+use super::{phys_to_virt, tlb_generation, with_kernel_memory};
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags},
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+// Processes get the lower half except for the first page, so that null pointers keep faulting
+pub const USER_SPACE_START: u64 = 0x1000;
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+const PCID_COUNT: u16 = 4096;
+// Bit 63 of a CR3 value, keeps the TLB entries of the new PCID when CR3 is written
+const NO_FLUSH: u64 = 1 << 63;
+
+// Physical address of the kernel's P4 table, set by `prepare_kernel_space`
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+// PCID 0 stays with the kernel's page table
+static NEXT_PCID: AtomicU16 = AtomicU16::new(1);
+// Id of the address space that was last activated with a PCID, 0 if none was
+static PCID_OWNERS: Mutex<[u64; PCID_COUNT as usize]> = Mutex::new([0; PCID_COUNT as usize]);
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    // `memory::install` hasn't been called yet
+    NotInstalled,
+    Vma(VmaError),
+    MapFailed(MapToError<Size4KiB>),
+    // The address doesn't lie in an area reserved with `AddressSpace::reserve`
+    NotUserAddress(VirtAddr),
+}
+
+/*
+Gives every P4 entry of kernel space a P3 table and enables PCIDs if the CPU has them
+
+Called by `memory::install` while the kernel's page table is active, before any address space
+exists. Costs one frame per 512 GiB of kernel space, the upper half of every address space then
+simply points to the same P3 tables.
+ */
+pub(super) fn prepare_kernel_space(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let (level_4_frame, _) = Cr3::read();
+    KERNEL_LEVEL_4_TABLE.store(level_4_frame.start_address().as_u64(), Ordering::SeqCst);
+
+    let first = usize::from(VirtAddr::new(KERNEL_SPACE_START).p4_index());
+    let last = usize::from(VirtAddr::new(KERNEL_SPACE_END - 1).p4_index());
+    let level_4_table = mapper.level_4_table();
+    for entry in level_4_table.iter_mut().take(last + 1).skip(first) {
+        if !entry.is_unused() {
+            continue;
+        }
+        let frame = frame_allocator
+            .allocate_frame()
+            .expect("no frames left for the kernel's page tables");
+        unsafe { table_mut(frame) }.zero();
+        frames::set_flags(
+            frame,
+            FrameFlags::KERNEL | FrameFlags::PAGE_TABLE | FrameFlags::PINNED,
+        );
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+
+    // CR4.PCIDE may only be set while the current PCID is 0, which it is at boot
+    let has_pcid = unsafe { __cpuid(1) }.ecx & (1 << 17) != 0;
+    if has_pcid {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+        PCID_ENABLED.store(true, Ordering::SeqCst);
+    }
+}
+
+// Switches back to the kernel's page table, e.g. before the active address space goes away
+pub fn activate_kernel() {
+    if let Some(frame) = kernel_level_4_frame() {
+        unsafe { write_cr3(frame, 0, false) };
+    }
+}
+
+// A P4 table sharing the kernel's mappings, with a user half of its own
+pub struct AddressSpace {
+    id: u64,
+    level_4_frame: PhysFrame,
+    pcid: Option<u16>,
+    // `memory::tlb_generation` at the last activation
+    tlb_generation: u64,
+    // The areas of the user half, the P4 entries shared with the kernel are reserved
+    vmas: VirtualMemoryManager,
+}
+
+impl AddressSpace {
+    // Allocates a P4 table and copies the kernel's entries into it
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let kernel_frame = kernel_level_4_frame().ok_or(AddressSpaceError::NotInstalled)?;
+        let level_4_frame = with_kernel_memory(|kernel| kernel.frame_allocator.allocate_frame())
+            .ok_or(AddressSpaceError::NotInstalled)?
+            .ok_or(AddressSpaceError::MapFailed(
+                MapToError::FrameAllocationFailed,
+            ))?;
+        frames::set_flags(level_4_frame, FrameFlags::PAGE_TABLE);
+
+        let level_4_table = unsafe { table_mut(level_4_frame) };
+        let kernel_table = unsafe { table_mut(kernel_frame) };
+        level_4_table.zero();
+        for (entry, kernel_entry) in level_4_table.iter_mut().zip(kernel_table.iter()) {
+            if !kernel_entry.is_unused() {
+                entry.set_addr(kernel_entry.addr(), kernel_entry.flags());
+            }
+        }
+
+        let mut address_space = AddressSpace {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            level_4_frame,
+            pcid: None,
+            tlb_generation: 0,
+            vmas: VirtualMemoryManager::new(USER_SPACE_START, USER_SPACE_END),
+        };
+        if PCID_ENABLED.load(Ordering::SeqCst) {
+            address_space.pcid = Some(allocate_pcid());
+        }
+        // dropping the address space frees the P4 table if this fails
+        address_space
+            .vmas
+            .reserve_used_entries(level_4_table)
+            .map_err(AddressSpaceError::Vma)?;
+        Ok(address_space)
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    // The PCID tagging the TLB entries of the address space, None if the CPU has no PCIDs
+    pub fn pcid(&self) -> Option<u16> {
+        self.pcid
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    // Reserves `size` bytes of the user half, mapped user accessible with the given flags by
+    // `map` or `map_to`. Returns the start of the area.
+    pub fn reserve(
+        &mut self,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<VirtAddr, AddressSpaceError> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        self.vmas
+            .reserve(size, 4096, VmaKind::User, flags)
+            .map_err(AddressSpaceError::Vma)
+    }
+
+    // Backs every page of the area starting at `start` with a fresh frame
+    pub fn map(&mut self, start: VirtAddr) -> Result<(), AddressSpaceError> {
+        self.user_area(start)?;
+        let mut mapper = unsafe { mapper(self.level_4_frame) };
+        let vmas = &self.vmas;
+        with_kernel_memory(|kernel| vmas.map(start, &mut mapper, &mut kernel.frame_allocator))
+            .ok_or(AddressSpaceError::NotInstalled)?
+            .map_err(AddressSpaceError::Vma)
+    }
+
+    /*
+    Maps a page of a reserved area to the given frame, with the area's flags
+
+    The address space takes over the caller's reference to the frame and drops it when the page is
+    unmapped. Unsafe because the caller must guarantee that the process may access the frame.
+     */
+    pub unsafe fn map_to(&mut self, page: Page, frame: PhysFrame) -> Result<(), AddressSpaceError> {
+        let area = self.user_area(page.start_address())?;
+        let mut mapper = mapper(self.level_4_frame);
+        with_kernel_memory(|kernel| {
+            mapper
+                .map_to(page, frame, area.flags, &mut PageTableFrames(&mut kernel.frame_allocator))
+                .map(|flush| flush.flush())
+        })
+        .ok_or(AddressSpaceError::NotInstalled)?
+        .map_err(AddressSpaceError::MapFailed)
+    }
+
+    // Unmaps the area starting at `start` and gives its frames and empty page tables back
+    pub fn unmap(&mut self, start: VirtAddr) -> Result<Vma, AddressSpaceError> {
+        self.user_area(start)?;
+        let mut mapper = unsafe { mapper(self.level_4_frame) };
+        let vmas = &mut self.vmas;
+        // the TLB flushes only reach the active address space, which is enough: the entries of an
+        // inactive one are gone after a CR3 switch, and unmapping invalidates the tagged ones
+        with_kernel_memory(|kernel| unsafe {
+            vmas.unmap(start, &mut mapper, &mut kernel.frame_allocator)
+        })
+        .ok_or(AddressSpaceError::NotInstalled)?
+        .map_err(AddressSpaceError::Vma)
+    }
+
+    // Switches to the address space
+    pub fn activate(&mut self) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let generation = tlb_generation();
+            match self.pcid {
+                Some(pcid) => {
+                    let mut owners = PCID_OWNERS.lock();
+                    let owner = &mut owners[usize::from(pcid)];
+                    let keep_tlb = *owner == self.id && self.tlb_generation == generation;
+                    *owner = self.id;
+                    unsafe { write_cr3(self.level_4_frame, pcid, keep_tlb) };
+                }
+                None => unsafe { write_cr3(self.level_4_frame, 0, false) },
+            }
+            self.tlb_generation = generation;
+        });
+    }
+
+    fn user_area(&self, addr: VirtAddr) -> Result<Vma, AddressSpaceError> {
+        self.vmas
+            .find(addr)
+            .filter(|area| area.kind == VmaKind::User)
+            .ok_or(AddressSpaceError::NotUserAddress(addr))
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            activate_kernel();
+        }
+        loop {
+            let area = self.vmas.areas().find(|area| area.kind == VmaKind::User);
+            match area {
+                Some(area) => self.unmap(area.start).expect("failed to unmap a user area"),
+                None => break,
+            };
+        }
+        // the P3 tables of the user half went with the areas, the shared ones stay untouched
+        frames::clear_flags(self.level_4_frame, FrameFlags::PAGE_TABLE);
+        let frame = self.level_4_frame;
+        with_kernel_memory(|kernel| {
+            if frames::put(frame) {
+                unsafe { kernel.frame_allocator.deallocate_frame(frame) };
+            }
+        });
+    }
+}
+
+fn kernel_level_4_frame() -> Option<PhysFrame> {
+    match KERNEL_LEVEL_4_TABLE.load(Ordering::SeqCst) {
+        0 => None,
+        addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+    }
+}
+
+fn allocate_pcid() -> u16 {
+    loop {
+        // the counter wraps at a multiple of `PCID_COUNT`, so the PCIDs simply repeat
+        let pcid = NEXT_PCID.fetch_add(1, Ordering::Relaxed) % PCID_COUNT;
+        if pcid != 0 {
+            return pcid;
+        }
+    }
+}
+
+unsafe fn write_cr3(frame: PhysFrame, pcid: u16, keep_tlb: bool) {
+    let mut bits = u64::from(pcid);
+    if keep_tlb {
+        bits |= NO_FLUSH;
+    }
+    // `Cr3Flags` only knows the caching bits, but `Cr3::write` puts all bits into the register
+    Cr3::write(frame, Cr3Flags::from_bits_unchecked(bits));
+}
+
+// Flushes the non-global TLB entries of the active PCID. Used instead of `tlb::flush_all`, which
+// writes back what `Cr3::read` returns and so loses the PCID of the active address space.
+pub(crate) fn flush_tlb() {
+    let cr3: u64;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        // the no-flush bit always reads as 0, so writing the value back flushes
+        asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+    }
+}
+
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+unsafe fn mapper(level_4_frame: PhysFrame) -> OffsetPageTable<'static> {
+    OffsetPageTable::new(table_mut(level_4_frame), phys_to_virt(PhysAddr::new(0)))
+}
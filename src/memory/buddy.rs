@@ -0,0 +1,339 @@
+use super::frames::{self, FrameDescriptor, FrameFlags};
+use super::physmap;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::{mem, slice, sync::atomic::Ordering};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+// Largest block order. An order `n` block is `2^n` physically contiguous frames (up to 4 MiB).
+pub const MAX_ORDER: usize = 10;
+
+const FRAME_SIZE: u64 = 4096;
+// Marks the end of a free list, frame 0 can't be used because it may be a valid frame
+const NONE: u64 = u64::MAX;
+
+// Header written into the first frame of every free block, linking the blocks of one order
+// into a doubly linked list so that a buddy can be unlinked in O(1) when merging.
+struct FreeBlock {
+    prev: u64,
+    next: u64,
+}
+
+// Buddy-system physical frame allocator seeded from the bootloader's memory map.
+//
+// Besides the intrusive free lists, the allocator needs to know whether a frame is the head of a
+// free block and of which order. It keeps that in the page-frame database (see `memory::frames`),
+// whose descriptor table it carves out of the first usable space large enough to hold it that
+// isn't reserved in `physmap`.
+pub struct BuddyFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    free_lists: [u64; MAX_ORDER + 1],
+    frames: &'static [FrameDescriptor],
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    /*
+    Create a BuddyFrameAllocator from the passed memory map
+
+    This function is unsafe because the caller must guarantee that the passed memory map is valid,
+    that all frames marked as `USABLE` in it are really unused and that the complete physical
+    memory is mapped at `physical_memory_offset`.
+     */
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // one descriptor per frame up to the end of the highest usable region
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_addr() / FRAME_SIZE)
+            .max()
+            .unwrap_or(0) as usize;
+        let map_size = (frame_count * mem::size_of::<FrameDescriptor>()) as u64;
+        let map_frames = (map_size + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        let map_start = usable_regions()
+            .find_map(|r| unreserved_space(r.range.start_addr(), r.range.end_addr(), map_frames))
+            .expect("no usable region is large enough for the frame database");
+        let map_end = map_start + map_frames * FRAME_SIZE;
+
+        let map_ptr: *mut FrameDescriptor = (physical_memory_offset + map_start).as_mut_ptr();
+        let descriptors = slice::from_raw_parts(map_ptr, frame_count);
+        init_descriptors(descriptors, memory_map, map_start..map_end);
+        frames::install(descriptors);
+
+        let mut allocator = BuddyFrameAllocator {
+            physical_memory_offset,
+            free_lists: [NONE; MAX_ORDER + 1],
+            frames: descriptors,
+            free_frames: 0,
+        };
+
+        for region in usable_regions() {
+            let start = align_up(region.range.start_addr(), FRAME_SIZE);
+            let end = region.range.end_addr() & !(FRAME_SIZE - 1);
+
+            // leave out the frames holding the descriptor table itself
+            if start < map_end && map_start < end {
+                allocator.add_range(start, map_start);
+                allocator.add_range(map_end, end);
+            } else {
+                allocator.add_range(start, end);
+            }
+        }
+
+        // ranges reserved before the allocator existed never make it into the free lists
+        physmap::for_each_reservation(|reservation| {
+            allocator
+                .reserve_range(reservation.start, reservation.end)
+                .expect("no frame is allocated yet");
+        });
+        allocator
+    }
+
+    // Number of frames that are currently free
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    // Allocates `2^order` physically contiguous frames, aligned to their combined size
+    // Returns the first frame of the block
+    pub fn allocate_contiguous(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NONE)?;
+        let index = unsafe { self.pop(current) };
+
+        // split the block, putting the upper halves back on the smaller free lists
+        while current > order {
+            current -= 1;
+            unsafe { self.push(current, index + (1 << current)) };
+        }
+
+        for descriptor in &self.frames[index..index + (1 << order)] {
+            descriptor.init(1, FrameFlags::empty());
+        }
+        self.free_frames -= 1 << order;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            index as u64 * FRAME_SIZE,
+        )))
+    }
+
+    /*
+    Frees a block of `2^order` frames returned by `allocate_contiguous`, merging it with its buddy
+    for as long as the buddy is free as well
+
+    This function is unsafe because the caller must guarantee that the block was allocated with
+    the same order and is no longer used.
+     */
+    pub unsafe fn deallocate_contiguous(&mut self, frame: PhysFrame, order: usize) {
+        let mut index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        debug_assert_eq!(index % (1 << order), 0, "misaligned block");
+        debug_assert_eq!(self.order(index), 0, "double free of {:?}", frame);
+
+        for descriptor in &self.frames[index..index + (1 << order)] {
+            descriptor.init(0, FrameFlags::empty());
+        }
+        self.free_frames += 1 << order;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            if buddy >= self.frames.len() || self.order(buddy) != order as u8 + 1 {
+                break;
+            }
+            self.remove(order, buddy);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.push(order, index);
+    }
+
+    /*
+    Takes the free frames of the physical range out of the free lists for good and pins them, so
+    they are never handed out
+
+    Fails without changing anything if a frame in the range is allocated, returning that frame.
+    Frames outside of usable memory are already unavailable and are skipped.
+     */
+    pub fn reserve_range(&mut self, start: PhysAddr, end: PhysAddr) -> Result<(), PhysFrame> {
+        let first = (start.as_u64() / FRAME_SIZE) as usize;
+        let end = (align_up(end.as_u64(), FRAME_SIZE) / FRAME_SIZE) as usize;
+        let frames = first..end.min(self.frames.len());
+
+        let in_use = frames.clone().find(|&index| {
+            let descriptor = &self.frames[index];
+            !descriptor.is_free() && !descriptor.flags().contains(FrameFlags::PINNED)
+        });
+        if let Some(index) = in_use {
+            let addr = PhysAddr::new(index as u64 * FRAME_SIZE);
+            return Err(PhysFrame::containing_address(addr));
+        }
+
+        for index in frames {
+            if self.frames[index].is_free() {
+                unsafe { self.take_frame(index) };
+                self.frames[index].init(1, FrameFlags::PINNED);
+            }
+        }
+        Ok(())
+    }
+
+    // Takes a single frame out of the free block containing it, giving the rest of the block back
+    // as smaller blocks. Returns false if the frame isn't free.
+    unsafe fn take_frame(&mut self, index: usize) -> bool {
+        let block = (0..=MAX_ORDER)
+            .map(|order| (order, index & !((1 << order) - 1)))
+            .find(|&(order, head)| self.order(head) == order as u8 + 1);
+        let (mut order, mut head) = match block {
+            Some(block) => block,
+            None => return false,
+        };
+
+        self.remove(order, head);
+        while order > 0 {
+            order -= 1;
+            let half = 1 << order;
+            if index >= head + half {
+                self.push(order, head);
+                head += half;
+            } else {
+                self.push(order, head + half);
+            }
+        }
+        self.free_frames -= 1;
+        true
+    }
+
+    // Frees every frame in the physical range, split into the largest naturally aligned blocks
+    unsafe fn add_range(&mut self, start: u64, end: u64) {
+        let mut index = (start / FRAME_SIZE) as usize;
+        let end = (end / FRAME_SIZE) as usize;
+        while index < end {
+            let mut order = MAX_ORDER;
+            while index % (1 << order) != 0 || index + (1 << order) > end {
+                order -= 1;
+            }
+            self.free_frames += 1 << order;
+            self.push(order, index);
+            index += 1 << order;
+        }
+    }
+
+    fn order(&self, index: usize) -> u8 {
+        self.frames[index].buddy_order.load(Ordering::Relaxed)
+    }
+
+    fn set_order(&self, index: usize, order: u8) {
+        self.frames[index].buddy_order.store(order, Ordering::Relaxed)
+    }
+
+    fn block(&self, index: usize) -> *mut FreeBlock {
+        (self.physical_memory_offset + index as u64 * FRAME_SIZE).as_mut_ptr()
+    }
+
+    unsafe fn push(&mut self, order: usize, index: usize) {
+        let head = self.free_lists[order];
+        if head != NONE {
+            (*self.block(head as usize)).prev = index as u64;
+        }
+        self.block(index).write(FreeBlock {
+            prev: NONE,
+            next: head,
+        });
+        self.free_lists[order] = index as u64;
+        self.set_order(index, order as u8 + 1);
+    }
+
+    unsafe fn pop(&mut self, order: usize) -> usize {
+        let index = self.free_lists[order] as usize;
+        self.remove(order, index);
+        index
+    }
+
+    unsafe fn remove(&mut self, order: usize, index: usize) {
+        let FreeBlock { prev, next } = self.block(index).read();
+        if prev == NONE {
+            self.free_lists[order] = next;
+        } else {
+            (*self.block(prev as usize)).next = next;
+        }
+        if next != NONE {
+            (*self.block(next as usize)).prev = prev;
+        }
+        self.set_order(index, 0);
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_contiguous(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate_contiguous(frame, 0)
+    }
+}
+
+// Start of the first `frames` frames in `start..end` that don't overlap a physmap reservation
+fn unreserved_space(start: u64, end: u64, frames: u64) -> Option<u64> {
+    let mut start = align_up(start, FRAME_SIZE);
+    while start + frames * FRAME_SIZE <= end {
+        let range = (PhysAddr::new(start), PhysAddr::new(start + frames * FRAME_SIZE));
+        match physmap::overlapping_reservation(range.0, range.1) {
+            Some(reservation) => start = align_up(reservation.end.as_u64(), FRAME_SIZE),
+            None => return Some(start),
+        }
+    }
+    None
+}
+
+// Sets up the descriptors from the memory map: usable frames start out free, everything else
+// (holes, firmware and the kernel's own frames) is pinned with a single reference
+fn init_descriptors(
+    descriptors: &[FrameDescriptor],
+    memory_map: &MemoryMap,
+    database: core::ops::Range<u64>,
+) {
+    for descriptor in descriptors {
+        descriptor.init(1, FrameFlags::PINNED);
+    }
+    for region in memory_map.iter() {
+        let flags = match region.region_type {
+            MemoryRegionType::Usable => None,
+            MemoryRegionType::PageTable => {
+                Some(FrameFlags::KERNEL | FrameFlags::PAGE_TABLE | FrameFlags::PINNED)
+            }
+            MemoryRegionType::Kernel
+            | MemoryRegionType::KernelStack
+            | MemoryRegionType::Bootloader
+            | MemoryRegionType::BootInfo
+            | MemoryRegionType::Package => Some(FrameFlags::KERNEL | FrameFlags::PINNED),
+            _ => Some(FrameFlags::PINNED),
+        };
+        let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+        let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+        for descriptor in descriptors.iter().take(end).skip(start) {
+            match flags {
+                Some(flags) => descriptor.init(1, flags),
+                None => descriptor.init(0, FrameFlags::empty()),
+            }
+        }
+    }
+    let start = (database.start / FRAME_SIZE) as usize;
+    let end = (database.end / FRAME_SIZE) as usize;
+    for descriptor in descriptors.iter().take(end).skip(start) {
+        descriptor.init(1, FrameFlags::KERNEL | FrameFlags::PINNED);
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
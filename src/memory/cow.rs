@@ -0,0 +1,128 @@
+// Copy-on-write mappings.
+//
+// Sharing a page maps the same frame at a second address, with both mappings made read-only and
+// marked with the `COPY_ON_WRITE` software bit. The first write through either mapping faults,
+// and the page fault handler gives the writer its own copy of the frame (or simply makes the page
+// writable again if no other mapping is left). The mappings of a frame are counted by the reference
+// count in the page-frame database.
+use super::{
+    frames::{self, PageTableFrames},
+    invalidate_tagged_tlb_entries, leaf_entry, phys_to_virt, try_with_kernel_memory,
+    vma::KERNEL_VMAS,
+    with_kernel_memory,
+};
+use core::ptr;
+use x86_64::{
+    instructions::tlb,
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+// Software bit marking read-only pages that become writable copies when written to
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Debug)]
+pub enum CowError {
+    SourceNotMapped,
+    // The destination page doesn't lie in an area reserved in `KERNEL_VMAS`
+    DestinationNotReserved,
+    // The source page maps a frame the page-frame database doesn't track, e.g. MMIO
+    UntrackedFrame,
+    MapFailed(MapToError<Size4KiB>),
+}
+
+// Maps `dst` to the frame behind `src`, turning both pages into copy-on-write mappings.
+// `dst` must lie in a reserved area of kernel address space and must not be mapped yet.
+pub fn share(src: Page, dst: Page) -> Result<(), CowError> {
+    if KERNEL_VMAS.lock().find(dst.start_address()).is_none() {
+        return Err(CowError::DestinationNotReserved);
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // copied out, the entry may not be borrowed while `map_to` changes the page tables
+        let (frame, mut flags) = {
+            let entry =
+                unsafe { leaf_entry(src.start_address()) }.ok_or(CowError::SourceNotMapped)?;
+            (PhysFrame::containing_address(entry.addr()), entry.flags())
+        };
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return Err(CowError::SourceNotMapped);
+        }
+        if frames::descriptor(frame).is_none() {
+            return Err(CowError::UntrackedFrame);
+        }
+
+        // only pages that were writable need the copy on write, read-only ones are simply shared
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags.remove(PageTableFlags::WRITABLE);
+            flags.insert(COPY_ON_WRITE);
+        }
+
+        frames::get(frame);
+        with_kernel_memory(|kernel| unsafe {
+            kernel
+                .mapper
+                .map_to(dst, frame, flags, &mut PageTableFrames(&mut kernel.frame_allocator))
+                .map(|flush| flush.flush())
+        })
+        .expect("kernel memory not installed")
+        .map_err(|err| {
+            frames::put(frame);
+            CowError::MapFailed(err)
+        })?;
+
+        let entry = unsafe { leaf_entry(src.start_address()) }.expect("source page went away");
+        entry.set_flags(flags);
+        tlb::flush(src.start_address());
+        invalidate_tagged_tlb_entries();
+        Ok(())
+    })
+}
+
+/*
+Resolves write faults on copy-on-write pages, called by the page fault handler
+
+Returns true if the faulting page now is a private, writable page and the write can be retried.
+ */
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let write_to_present_page =
+        PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION;
+    if !error_code.contains(write_to_present_page) {
+        return false;
+    }
+    let entry = match unsafe { leaf_entry(addr) } {
+        Some(entry) if entry.flags().contains(COPY_ON_WRITE) => entry,
+        _ => return false,
+    };
+    let frame = PhysFrame::containing_address(entry.addr());
+    let mut flags = entry.flags();
+    flags.remove(COPY_ON_WRITE);
+    flags.insert(PageTableFlags::WRITABLE);
+
+    if frames::refcount(frame) > 1 {
+        // other pages still map the frame, so the writer gets a copy
+        let copy = match try_with_kernel_memory(|kernel| kernel.frame_allocator.allocate_frame()) {
+            Some(Some(copy)) => copy,
+            _ => return false,
+        };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                Size4KiB::SIZE as usize,
+            );
+        }
+        frames::put(frame);
+        entry.set_addr(copy.start_address(), flags);
+    } else {
+        // last mapping of the frame, it can just be written to
+        entry.set_flags(flags);
+    }
+    tlb::flush(addr);
+    // other address spaces sharing the page may still have the old entry cached
+    invalidate_tagged_tlb_entries();
+    true
+}
@@ -0,0 +1,70 @@
+use super::frames::PageTableFrames;
+use super::vma::{VmaKind, KERNEL_VMAS};
+use super::{phys_to_virt, try_with_kernel_memory};
+use core::ptr;
+use x86_64::{
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags},
+    VirtAddr,
+};
+
+// Reserves `size` bytes of kernel address space whose pages only get backed by (zeroed) frames
+// when they are first touched. Returns the start address of the range.
+pub fn reserve_lazy(size: u64, flags: PageTableFlags) -> Option<VirtAddr> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        KERNEL_VMAS
+            .lock()
+            .reserve_demand_paged(size, 4096, VmaKind::DemandPaged, flags)
+            .ok()
+    })
+}
+
+/*
+Tries to resolve a page fault by backing the faulting page, called by the page fault handler
+
+Returns true if the fault was a not-present access to a demand paged area that is allowed by the
+area's flags and the page is mapped now, so the faulting instruction can simply be retried. Returns
+false for every other fault, including ones that happen while the kernel memory or the area list
+is locked.
+ */
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let area = match KERNEL_VMAS.try_lock().and_then(|vmas| vmas.find(addr)) {
+        Some(area) if area.demand_paged => area,
+        _ => return false,
+    };
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !area.flags.contains(PageTableFlags::WRITABLE)
+    {
+        return false;
+    }
+
+    let page = Page::containing_address(addr);
+    try_with_kernel_memory(|kernel| {
+        let frame = match kernel.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        unsafe {
+            let frame_ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+            ptr::write_bytes(frame_ptr, 0, 4096);
+
+            match kernel
+                .mapper
+                .map_to(page, frame, area.flags, &mut PageTableFrames(&mut kernel.frame_allocator))
+            {
+                Ok(flush) => {
+                    flush.flush();
+                    true
+                }
+                Err(_) => {
+                    kernel.frame_allocator.deallocate_frame(frame);
+                    false
+                }
+            }
+        }
+    })
+    .unwrap_or(false)
+}
@@ -0,0 +1,195 @@
+// Page-frame database: one descriptor per physical frame of RAM, recording how many mappings
+// reference the frame and what it is used for.
+//
+// The descriptor table is carved out of usable memory by `BuddyFrameAllocator::init`, which also
+// keeps its free block orders in the descriptors. Every field is atomic, so descriptors can be read
+// and updated without a lock, including from exception handlers.
+use core::{
+    fmt, ops,
+    sync::atomic::{AtomicPtr, AtomicU16, AtomicU8, AtomicUsize, Ordering},
+};
+use x86_64::{
+    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+const FRAME_SIZE: u64 = 4096;
+
+// What a frame is used for, several flags may be set at once
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FrameFlags(u8);
+
+impl FrameFlags {
+    pub const KERNEL: FrameFlags = FrameFlags(1 << 0);
+    pub const USER: FrameFlags = FrameFlags(1 << 1);
+    pub const PAGE_TABLE: FrameFlags = FrameFlags(1 << 2);
+    // Target of device DMA, must stay at its physical address
+    pub const DMA: FrameFlags = FrameFlags(1 << 3);
+    // Never given back to the frame allocator, e.g. firmware tables or the kernel image
+    pub const PINNED: FrameFlags = FrameFlags(1 << 4);
+
+    const NAMES: [(FrameFlags, &'static str); 5] = [
+        (Self::KERNEL, "KERNEL"),
+        (Self::USER, "USER"),
+        (Self::PAGE_TABLE, "PAGE_TABLE"),
+        (Self::DMA, "DMA"),
+        (Self::PINNED, "PINNED"),
+    ];
+
+    pub const fn empty() -> Self {
+        FrameFlags(0)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, other: FrameFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl ops::BitOr for FrameFlags {
+    type Output = FrameFlags;
+
+    fn bitor(self, other: FrameFlags) -> FrameFlags {
+        FrameFlags(self.0 | other.0)
+    }
+}
+
+impl fmt::Debug for FrameFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = Self::NAMES.iter().filter(|(flag, _)| self.contains(*flag));
+        match names.next() {
+            Some((_, name)) => write!(f, "{}", name)?,
+            None => return write!(f, "(empty)"),
+        }
+        for (_, name) in names {
+            write!(f, " | {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+#[repr(C)]
+pub struct FrameDescriptor {
+    // Number of users of the frame, 0 while it is free
+    refcount: AtomicU16,
+    flags: AtomicU8,
+    // Order + 1 of the free buddy block starting at this frame, 0 if the frame isn't the head of one
+    pub(super) buddy_order: AtomicU8,
+}
+
+impl FrameDescriptor {
+    pub(super) fn init(&self, refcount: u16, flags: FrameFlags) {
+        self.refcount.store(refcount, Ordering::Relaxed);
+        self.flags.store(flags.bits(), Ordering::Relaxed);
+        self.buddy_order.store(0, Ordering::Relaxed);
+    }
+
+    pub fn refcount(&self) -> u16 {
+        self.refcount.load(Ordering::Relaxed)
+    }
+
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags(self.flags.load(Ordering::Relaxed))
+    }
+
+    pub fn is_free(&self) -> bool {
+        self.refcount() == 0
+    }
+}
+
+// The table is published once by the frame allocator and never freed
+static DESCRIPTORS: AtomicPtr<FrameDescriptor> = AtomicPtr::new(core::ptr::null_mut());
+static DESCRIPTOR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/*
+Makes the given descriptor table the page-frame database, `descriptors[n]` describing frame `n`
+
+Unsafe because the table must stay valid and be used for nothing else for the rest of the
+kernel's lifetime.
+ */
+pub(super) unsafe fn install(descriptors: &'static [FrameDescriptor]) {
+    DESCRIPTOR_COUNT.store(0, Ordering::SeqCst);
+    DESCRIPTORS.store(descriptors.as_ptr() as *mut _, Ordering::SeqCst);
+    DESCRIPTOR_COUNT.store(descriptors.len(), Ordering::SeqCst);
+}
+
+fn descriptors() -> &'static [FrameDescriptor] {
+    let count = DESCRIPTOR_COUNT.load(Ordering::SeqCst);
+    let ptr = DESCRIPTORS.load(Ordering::SeqCst);
+    if ptr.is_null() {
+        return &[];
+    }
+    unsafe { core::slice::from_raw_parts(ptr, count) }
+}
+
+// Returns the descriptor of the frame, None for frames outside of RAM (e.g. MMIO) or while the
+// database doesn't exist yet
+pub fn descriptor(frame: PhysFrame) -> Option<&'static FrameDescriptor> {
+    descriptors().get((frame.start_address().as_u64() / FRAME_SIZE) as usize)
+}
+
+// Number of users of the frame, 1 for frames the database doesn't track
+pub fn refcount(frame: PhysFrame) -> u16 {
+    descriptor(frame).map_or(1, |d| d.refcount())
+}
+
+// Takes another reference to a frame that is already in use, e.g. when mapping it a second time
+pub fn get(frame: PhysFrame) {
+    if let Some(descriptor) = descriptor(frame) {
+        let old = descriptor.refcount.fetch_add(1, Ordering::Relaxed);
+        debug_assert!(old != 0, "reference taken to free frame {:?}", frame);
+    }
+}
+
+// Drops a reference to the frame. Returns true if it was the last one, in which case the caller
+// has to give the frame back to the frame allocator.
+pub fn put(frame: PhysFrame) -> bool {
+    match descriptor(frame) {
+        Some(descriptor) => {
+            let old = descriptor.refcount.fetch_sub(1, Ordering::Relaxed);
+            debug_assert!(old != 0, "reference dropped to free frame {:?}", frame);
+            old == 1
+        }
+        None => true,
+    }
+}
+
+pub fn set_flags(frame: PhysFrame, flags: FrameFlags) {
+    if let Some(descriptor) = descriptor(frame) {
+        descriptor.flags.fetch_or(flags.bits(), Ordering::Relaxed);
+    }
+}
+
+pub fn clear_flags(frame: PhysFrame, flags: FrameFlags) {
+    if let Some(descriptor) = descriptor(frame) {
+        descriptor.flags.fetch_and(!flags.bits(), Ordering::Relaxed);
+    }
+}
+
+// Iterates over every frame the database tracks, for diagnostics
+pub fn frames() -> impl Iterator<Item = (PhysFrame, &'static FrameDescriptor)> {
+    descriptors().iter().enumerate().map(|(index, descriptor)| {
+        let frame = PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE));
+        (frame, descriptor)
+    })
+}
+
+// Wraps the frame allocator passed to `Mapper::map_to`, which only takes frames from it for new
+// page tables, and marks those frames as `PAGE_TABLE`. Freeing a table resets its descriptor, so
+// the flag goes away with it.
+pub struct PageTableFrames<'a, A>(pub &'a mut A);
+
+unsafe impl<A: FrameAllocator<Size4KiB>> FrameAllocator<Size4KiB> for PageTableFrames<'_, A> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.0.allocate_frame()?;
+        set_flags(frame, FrameFlags::PAGE_TABLE);
+        Some(frame)
+    }
+}
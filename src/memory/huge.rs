@@ -0,0 +1,164 @@
+// Mapping with 2 MiB and 1 GiB pages.
+//
+// A huge page needs both its virtual and its physical address aligned to its size. The helpers
+// here map a range piece by piece, always using the largest page that fits at the current
+// position, so ranges that aren't fully aligned still get huge pages in their aligned middle.
+use super::buddy::BuddyFrameAllocator;
+use super::frames::PageTableFrames;
+use core::arch::x86_64::__cpuid;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+// Buddy order of a block of frames backing one 2 MiB page
+const ORDER_2MIB: usize = 9;
+
+// Number of pages of every size used for a mapping
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageCounts {
+    pub pages_4kib: usize,
+    pub pages_2mib: usize,
+    pub pages_1gib: usize,
+}
+
+impl PageCounts {
+    // Number of bytes the pages map
+    pub fn bytes(&self) -> u64 {
+        self.pages_4kib as u64 * Size4KiB::SIZE
+            + self.pages_2mib as u64 * Size2MiB::SIZE
+            + self.pages_1gib as u64 * Size1GiB::SIZE
+    }
+}
+
+// Whether the CPU supports 1 GiB pages (2 MiB pages are always available in long mode)
+pub fn supports_1gib_pages() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+/*
+Maps the physical range `phys..phys + size` to `virt`, using 1 GiB and 2 MiB pages wherever both
+addresses are suitably aligned, e.g. for framebuffers and other large device memory
+
+`frame_allocator` is only used for page tables. Unsafe because the caller must guarantee that
+mapping the physical range doesn't break memory safety.
+ */
+pub unsafe fn map_physical_range(
+    mapper: &mut OffsetPageTable,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<PageCounts, MapToError<Size4KiB>> {
+    let use_1gib = supports_1gib_pages();
+    let mut counts = PageCounts::default();
+    let mut offset = 0;
+    while offset < size {
+        let (virt, phys, left) = (virt + offset, phys + offset, size - offset);
+        if use_1gib && fits::<Size1GiB>(virt, phys, left) {
+            let frame = PhysFrame::<Size1GiB>::containing_address(phys);
+            map_huge(mapper, Page::containing_address(virt), frame, flags, frame_allocator)?;
+            counts.pages_1gib += 1;
+            offset += Size1GiB::SIZE;
+        } else if fits::<Size2MiB>(virt, phys, left) {
+            let frame = PhysFrame::<Size2MiB>::containing_address(phys);
+            map_huge(mapper, Page::containing_address(virt), frame, flags, frame_allocator)?;
+            counts.pages_2mib += 1;
+            offset += Size2MiB::SIZE;
+        } else {
+            let frame = PhysFrame::<Size4KiB>::containing_address(phys);
+            let page = Page::<Size4KiB>::containing_address(virt);
+            mapper.map_to(page, frame, flags, &mut PageTableFrames(frame_allocator))?.flush();
+            counts.pages_4kib += 1;
+            offset += Size4KiB::SIZE;
+        }
+    }
+    Ok(counts)
+}
+
+/*
+Maps `size` bytes at `virt` to newly allocated frames, using 2 MiB pages wherever `virt` is aligned
+and the frame allocator still has a free 2 MiB block
+
+1 GiB pages aren't used since the frame allocator's largest blocks are smaller. Stops at the first
+page that can't be mapped and returns the pages mapped up to there.
+ */
+pub fn map_backed_range(
+    mapper: &mut OffsetPageTable,
+    virt: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut BuddyFrameAllocator,
+) -> PageCounts {
+    let mut counts = PageCounts::default();
+    let mut offset = 0;
+    while offset < size {
+        let (virt, left) = (virt + offset, size - offset);
+        if virt.is_aligned(Size2MiB::SIZE) && left >= Size2MiB::SIZE {
+            if let Some(block) = frame_allocator.allocate_contiguous(ORDER_2MIB) {
+                let frame = PhysFrame::<Size2MiB>::containing_address(block.start_address());
+                let page = Page::containing_address(virt);
+                match unsafe { map_huge(mapper, page, frame, flags, frame_allocator) } {
+                    Ok(()) => {
+                        counts.pages_2mib += 1;
+                        offset += Size2MiB::SIZE;
+                        continue;
+                    }
+                    Err(_) => {
+                        unsafe { frame_allocator.deallocate_contiguous(block, ORDER_2MIB) };
+                        break;
+                    }
+                }
+            }
+        }
+
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => break,
+        };
+        let page = Page::<Size4KiB>::containing_address(virt);
+        match unsafe { mapper.map_to(page, frame, flags, &mut PageTableFrames(frame_allocator)) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                break;
+            }
+        }
+        counts.pages_4kib += 1;
+        offset += Size4KiB::SIZE;
+    }
+    counts
+}
+
+// Whether a page of size `S` can map `virt` to `phys` without running past `left` bytes
+fn fits<S: PageSize>(virt: VirtAddr, phys: PhysAddr, left: u64) -> bool {
+    virt.is_aligned(S::SIZE) && phys.is_aligned(S::SIZE) && left >= S::SIZE
+}
+
+unsafe fn map_huge<S: PageSize>(
+    mapper: &mut OffsetPageTable,
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    match mapper.map_to(page, frame, flags, &mut PageTableFrames(frame_allocator)) {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(MapToError::FrameAllocationFailed) => Err(MapToError::FrameAllocationFailed),
+        Err(MapToError::ParentEntryHugePage) => Err(MapToError::ParentEntryHugePage),
+        Err(MapToError::PageAlreadyMapped(frame)) => Err(MapToError::PageAlreadyMapped(
+            PhysFrame::containing_address(frame.start_address()),
+        )),
+    }
+}
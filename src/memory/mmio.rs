@@ -0,0 +1,244 @@
+// Mapping device memory (`ioremap`).
+//
+// Caching is chosen per page through the PAT, PCD and PWT bits of the page table entry, which
+// together select one of the eight entries of the PAT MSR. `init_pat` keeps the power-on defaults
+// in the first four entries and makes entry 4 write-combining:
+//
+//     index  PAT PCD PWT  type
+//     0       0   0   0   write-back
+//     1       0   0   1   write-through
+//     3       0   1   1   uncached
+//     4       1   0   0   write-combining
+//
+// In a 4 KiB page table entry the PAT bit is bit 7, the one used as `HUGE_PAGE` in the higher
+// levels. Its position differs for huge pages, so write-combining mappings always use 4 KiB pages.
+// `Mapper::map_to` refuses entries with bit 7 set, so those pages are mapped without it and the bit
+// is set in the entry afterwards.
+use super::frames::PageTableFrames;
+use super::vma::{VmaError, VmaKind, KERNEL_VMAS};
+use super::{huge, leaf_entry, protect, unmap_io_range, with_kernel_memory};
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{mem, ptr};
+use x86_64::{
+    instructions::tlb,
+    registers::model_specific::Msr,
+    structures::paging::{
+        mapper::MapToError, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size2MiB,
+        Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const IA32_PAT: u32 = 0x277;
+// WB, WT, UC-, UC, WC, WT, UC-, UC
+const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+const PAT_BIT: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// How the CPU caches accesses to a mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    // For device registers, every access goes to the device in program order
+    Uncached,
+    // Writes may be combined and reordered, for framebuffers. Falls back to uncached if the CPU
+    // has no PAT.
+    WriteCombining,
+}
+
+impl CacheMode {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining if PAT_ENABLED.load(Ordering::Relaxed) => PAT_BIT,
+            CacheMode::Uncached | CacheMode::WriteCombining => {
+                PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum IoRemapError {
+    // No virtual address space left
+    Vma(VmaError),
+    MapFailed(MapToError<Size4KiB>),
+    // `memory::install` hasn't been called yet
+    NotInstalled,
+}
+
+// Programs the PAT MSR with the layout described above, called once at boot
+pub fn init_pat() {
+    let has_pat = unsafe { __cpuid(1) }.edx & (1 << 16) != 0;
+    if has_pat {
+        let mut pat = Msr::new(IA32_PAT);
+        unsafe { pat.write(PAT_VALUE) };
+        PAT_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+// A mapping of device memory, unmapped when dropped
+#[derive(Debug)]
+pub struct IoMapping {
+    // Start of the mapped pages, `addr` lies inside the first one
+    area_start: VirtAddr,
+    addr: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    cache_mode: CacheMode,
+}
+
+impl IoMapping {
+    // Virtual address of the first mapped byte, corresponding to the physical address passed to
+    // `ioremap`
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.addr
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn cache_mode(&self) -> CacheMode {
+        self.cache_mode
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.addr.as_mut_ptr()
+    }
+
+    // Reads a register at the given byte offset with a single volatile access
+    pub fn read<T: Copy>(&self, offset: u64) -> T {
+        unsafe { ptr::read_volatile(self.register(offset)) }
+    }
+
+    // Writes a register at the given byte offset with a single volatile access
+    pub fn write<T: Copy>(&self, offset: u64, value: T) {
+        unsafe { ptr::write_volatile(self.register(offset), value) }
+    }
+
+    fn register<T>(&self, offset: u64) -> *mut T {
+        let size = mem::size_of::<T>() as u64;
+        assert!(
+            offset.checked_add(size).map_or(false, |end| end <= self.size),
+            "register at {:#x} outside of the {:#x} byte mapping",
+            offset,
+            self.size
+        );
+        assert_eq!(offset % mem::align_of::<T>() as u64, 0, "misaligned register");
+        (self.addr + offset).as_mut_ptr()
+    }
+}
+
+impl Drop for IoMapping {
+    fn drop(&mut self) {
+        let first = Page::<Size4KiB>::containing_address(self.area_start);
+        let last = Page::containing_address(self.addr + (self.size - 1));
+        with_kernel_memory(|kernel| unsafe {
+            unmap_io_range(
+                &mut kernel.mapper,
+                Page::range_inclusive(first, last),
+                &mut kernel.frame_allocator,
+            )
+            .expect("failed to unmap I/O memory")
+        });
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            KERNEL_VMAS.lock().release(self.area_start)
+        })
+        .expect("I/O mapping without area");
+    }
+}
+
+/*
+Maps `size` bytes of device memory starting at `phys` into kernel space with the given caching
+
+Large mappings use huge pages where the physical address allows, except for write-combining ones.
+Unsafe because the caller must guarantee that the physical range is device memory (or memory it
+owns) and that accessing it with the given cache mode doesn't break memory safety.
+ */
+pub unsafe fn ioremap(
+    phys: PhysAddr,
+    size: u64,
+    cache_mode: CacheMode,
+) -> Result<IoMapping, IoRemapError> {
+    assert!(size > 0, "empty I/O mapping");
+    let frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let offset = phys - frame.start_address();
+    let map_size = align_up(offset + size, Size4KiB::SIZE);
+
+    let cache_flags = cache_mode.flags();
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | protect::no_execute() | cache_flags;
+    let use_huge_pages = !cache_flags.contains(PAT_BIT) && map_size >= Size2MiB::SIZE;
+
+    // give the virtual range the same offset into a 2 MiB page as the physical one, so the middle
+    // of large ranges can be mapped with huge pages
+    let align = if use_huge_pages { Size2MiB::SIZE } else { Size4KiB::SIZE };
+    let skew = frame.start_address().as_u64() % align;
+    let area_start = x86_64::instructions::interrupts::without_interrupts(|| {
+        KERNEL_VMAS
+            .lock()
+            .reserve(map_size + skew, align, VmaKind::Mmio, flags)
+    })
+    .map_err(IoRemapError::Vma)?;
+    let virt = area_start + skew;
+
+    let mapped = with_kernel_memory(|kernel| {
+        if use_huge_pages {
+            huge::map_physical_range(
+                &mut kernel.mapper,
+                virt,
+                frame.start_address(),
+                map_size,
+                flags,
+                &mut kernel.frame_allocator,
+            )
+            .map(|_| ())
+        } else {
+            let pages = Page::range(
+                Page::containing_address(virt),
+                Page::containing_address(virt + map_size),
+            );
+            let frames = PhysFrame::range(frame, frame + map_size / Size4KiB::SIZE);
+            pages.zip(frames).try_for_each(|(page, frame)| {
+                let table_allocator = &mut PageTableFrames(&mut kernel.frame_allocator);
+                kernel
+                    .mapper
+                    .map_to(page, frame, flags - PAT_BIT, table_allocator)?
+                    .flush();
+                if flags.contains(PAT_BIT) {
+                    let entry = leaf_entry(page.start_address()).expect("page was just mapped");
+                    entry.set_addr(frame.start_address(), flags);
+                    tlb::flush(page.start_address());
+                }
+                Ok(())
+            })
+        }
+    });
+
+    let mapping = IoMapping {
+        area_start,
+        addr: virt + offset,
+        phys,
+        size,
+        cache_mode,
+    };
+    match mapped {
+        Some(Ok(())) => Ok(mapping),
+        // dropping the handle unmaps whatever was mapped before the error
+        Some(Err(err)) => Err(IoRemapError::MapFailed(err)),
+        None => Err(IoRemapError::NotInstalled),
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,225 @@
+// The physical memory map: a boot report of what the firmware and bootloader left us, and
+// reservations of physical ranges the frame allocator must never hand out (firmware tables, device
+// memory, DMA buffers below 16 MiB and the like).
+use super::with_kernel_memory;
+use crate::{println, serial_println};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::fmt;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+const MAX_RESERVATIONS: usize = 32;
+
+// The bootloader's memory map, set by `init`
+static MEMORY_MAP: Mutex<Option<&'static MemoryMap>> = Mutex::new(None);
+
+static RESERVATIONS: Mutex<[Option<Reservation>; MAX_RESERVATIONS]> =
+    Mutex::new([None; MAX_RESERVATIONS]);
+
+// A physical range the frame allocator never hands out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reservation {
+    pub start: PhysAddr,
+    // First address behind the range
+    pub end: PhysAddr,
+    // What the range is used for, shown in reports
+    pub name: &'static str,
+}
+
+#[derive(Debug)]
+pub enum ReserveError {
+    // The range overlaps an existing reservation
+    Overlap(Reservation),
+    TooManyReservations,
+    // The frame allocator already handed out a frame of the range
+    InUse(PhysAddr),
+}
+
+// Remembers the memory map for queries and reserves the ranges every kernel must keep its hands
+// off. Call it before creating the frame allocator.
+pub fn init(memory_map: &'static MemoryMap) {
+    *MEMORY_MAP.lock() = Some(memory_map);
+    reserve(PhysAddr::new(0xb8000), 80 * 25 * 2, "VGA text buffer")
+        .expect("failed to reserve the VGA text buffer");
+}
+
+/*
+Reserves the physical range `start..start + size`, extended to whole frames
+
+Frame allocators created afterwards leave the range out. The frames of the allocator installed
+with `memory::install` are taken out of its free lists right away, which fails if one of them is
+already in use.
+ */
+pub fn reserve(start: PhysAddr, size: u64, name: &'static str) -> Result<(), ReserveError> {
+    let reservation = Reservation {
+        start: start.align_down(4096u64),
+        end: (start + size).align_up(4096u64),
+        name,
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut reservations = RESERVATIONS.lock();
+        let overlap = reservations
+            .iter()
+            .flatten()
+            .find(|r| r.start < reservation.end && reservation.start < r.end);
+        if let Some(&overlap) = overlap {
+            return Err(ReserveError::Overlap(overlap));
+        }
+        let slot = reservations
+            .iter_mut()
+            .find(|r| r.is_none())
+            .ok_or(ReserveError::TooManyReservations)?;
+
+        let result = with_kernel_memory(|kernel| {
+            kernel
+                .frame_allocator
+                .reserve_range(reservation.start, reservation.end)
+        });
+        if let Some(Err(frame)) = result {
+            return Err(ReserveError::InUse(frame.start_address()));
+        }
+        *slot = Some(reservation);
+        Ok(())
+    })
+}
+
+/*
+Reserves `size` bytes of usable memory below `limit`, aligned to `align`, e.g. for ISA DMA buffers
+which have to lie below 16 MiB
+
+Returns the start of the range, None if no free range is left. Needs `init` to have been called.
+ */
+pub fn reserve_below(limit: u64, size: u64, align: u64, name: &'static str) -> Option<PhysAddr> {
+    let memory_map = (*MEMORY_MAP.lock())?;
+    let align = align.max(4096);
+    let usable = memory_map
+        .iter()
+        .filter(|r| r.region_type == MemoryRegionType::Usable);
+    for region in usable {
+        let mut start = align_up(region.range.start_addr(), align);
+        let end = region.range.end_addr().min(limit);
+        while start + size <= end {
+            match reserve(PhysAddr::new(start), size, name) {
+                Ok(()) => return Some(PhysAddr::new(start)),
+                Err(ReserveError::TooManyReservations) => return None,
+                Err(ReserveError::Overlap(r)) => start = align_up(r.end.as_u64(), align),
+                Err(ReserveError::InUse(addr)) => start = align_up(addr.as_u64() + 1, align),
+            }
+        }
+    }
+    None
+}
+
+// Returns the reservation containing the address
+pub fn reservation(addr: PhysAddr) -> Option<Reservation> {
+    let reservations = RESERVATIONS.lock();
+    let reservation = reservations
+        .iter()
+        .flatten()
+        .find(|r| r.start <= addr && addr < r.end);
+    reservation.copied()
+}
+
+// Returns a reservation overlapping the range `start..end`
+pub fn overlapping_reservation(start: PhysAddr, end: PhysAddr) -> Option<Reservation> {
+    let reservations = RESERVATIONS.lock();
+    let reservation = reservations
+        .iter()
+        .flatten()
+        .find(|r| r.start < end && start < r.end);
+    reservation.copied()
+}
+
+pub fn for_each_reservation(mut f: impl FnMut(&Reservation)) {
+    let reservations = *RESERVATIONS.lock();
+    reservations.iter().flatten().for_each(|r| f(r));
+}
+
+// Type of the memory map region containing the address, None if the address isn't in the map
+// or `init` hasn't been called
+pub fn region_type(addr: PhysAddr) -> Option<MemoryRegionType> {
+    let memory_map = (*MEMORY_MAP.lock())?;
+    let addr = addr.as_u64();
+    memory_map
+        .iter()
+        .find(|r| r.range.start_addr() <= addr && addr < r.range.end_addr())
+        .map(|r| r.region_type)
+}
+
+// Bytes of memory per kind of region
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryReport {
+    pub total: u64,
+    pub usable: u64,
+    // Memory the kernel already uses: its image, stack and the boot page tables
+    pub kernel: u64,
+    // The bootloader, boot information and other memory it marked as in use
+    pub bootloader: u64,
+    pub acpi_reclaimable: u64,
+    pub acpi_nvs: u64,
+    // Reserved by the firmware, faulty or otherwise unusable
+    pub reserved: u64,
+}
+
+impl MemoryReport {
+    pub fn new(memory_map: &MemoryMap) -> Self {
+        let mut report = MemoryReport::default();
+        for region in memory_map.iter() {
+            let size = region.range.end_addr() - region.range.start_addr();
+            report.total += size;
+            *match region.region_type {
+                MemoryRegionType::Usable => &mut report.usable,
+                MemoryRegionType::Kernel
+                | MemoryRegionType::KernelStack
+                | MemoryRegionType::PageTable => &mut report.kernel,
+                MemoryRegionType::Bootloader
+                | MemoryRegionType::BootInfo
+                | MemoryRegionType::Package
+                | MemoryRegionType::InUse => &mut report.bootloader,
+                MemoryRegionType::AcpiReclaimable => &mut report.acpi_reclaimable,
+                MemoryRegionType::AcpiNvs => &mut report.acpi_nvs,
+                _ => &mut report.reserved,
+            } += size;
+        }
+        report
+    }
+}
+
+impl fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows = [
+            ("total", self.total),
+            ("usable", self.usable),
+            ("kernel", self.kernel),
+            ("bootloader", self.bootloader),
+            ("ACPI reclaimable", self.acpi_reclaimable),
+            ("ACPI NVS", self.acpi_nvs),
+            ("reserved", self.reserved),
+        ];
+        for (name, bytes) in rows.iter() {
+            writeln!(f, "{:<17} {:>8} KiB", name, bytes / 1024)?;
+        }
+        Ok(())
+    }
+}
+
+// Prints the memory report and the regions of the memory map over serial and VGA
+pub fn print_report(memory_map: &MemoryMap) {
+    let report = MemoryReport::new(memory_map);
+    println!("{}", report);
+    serial_println!("{}", report);
+    for region in memory_map.iter() {
+        let (start, end) = (region.range.start_addr(), region.range.end_addr());
+        println!("{:#012x}-{:#012x} {:?}", start, end, region.region_type);
+        serial_println!("{:#012x}-{:#012x} {:?}", start, end, region.region_type);
+    }
+    for_each_reservation(|r| {
+        let (start, end) = (r.start.as_u64(), r.end.as_u64());
+        println!("{:#012x}-{:#012x} reserved for {}", start, end, r.name);
+        serial_println!("{:#012x}-{:#012x} reserved for {}", start, end, r.name);
+    });
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
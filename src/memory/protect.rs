@@ -0,0 +1,157 @@
+// Page protections for the kernel: no-execute, supervisor write protection and W^X for the kernel
+// image.
+//
+// The kernel image is remapped segment by segment from its ELF program headers, which the linker
+// places right behind the ELF header at the start of the first loaded segment. Code ends up
+// read-only and executable, read-only data read-only and everything writable non-executable.
+use core::arch::x86_64::__cpuid;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        mapper::FlagUpdateError, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+static NO_EXECUTE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    // Defined by the linker
+    static __ehdr_start: ElfHeader;
+}
+
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_header_offset: u64,
+    section_header_offset: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_size: u16,
+    program_header_count: u16,
+    section_header_size: u16,
+    section_header_count: u16,
+    section_names_index: u16,
+}
+
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    virt_addr: u64,
+    phys_addr: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+
+// Sets EFER.NXE if the CPU supports it, so that `NO_EXECUTE` page table entries become valid
+pub fn enable_no_execute() {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    let supported =
+        max_extended_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 20) != 0;
+    if supported {
+        unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+        NO_EXECUTE_ENABLED.store(true, Ordering::SeqCst);
+    }
+}
+
+// `NO_EXECUTE` if it is enabled, empty otherwise (the bit is reserved while EFER.NXE is clear, so
+// setting it would make every access to the page fault)
+pub fn no_execute() -> PageTableFlags {
+    if NO_EXECUTE_ENABLED.load(Ordering::SeqCst) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+// Sets CR0.WP, so that read-only pages are read-only for the kernel as well
+pub fn enable_write_protect() {
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+}
+
+fn kernel_segments() -> impl Iterator<Item = &'static ProgramHeader> {
+    let header = unsafe { &__ehdr_start };
+    assert_eq!(&header.ident[..4], b"\x7fELF", "no ELF header in front of the kernel");
+    let program_headers = unsafe {
+        let start = (header as *const ElfHeader as *const u8)
+            .add(header.program_header_offset as usize);
+        slice::from_raw_parts(
+            start as *const ProgramHeader,
+            header.program_header_count as usize,
+        )
+    };
+    program_headers
+        .iter()
+        .filter(|segment| segment.kind == PT_LOAD && segment.memory_size > 0)
+}
+
+fn segment_pages(segment: &ProgramHeader) -> impl Iterator<Item = Page> {
+    let start = VirtAddr::new(segment.virt_addr);
+    let end = start + (segment.memory_size - 1);
+    Page::range_inclusive(Page::containing_address(start), Page::containing_address(end))
+}
+
+// Flags of a page of the kernel image. Pages shared by two segments get the permissions of both.
+fn kernel_page_flags(page: Page) -> PageTableFlags {
+    let mut writable = false;
+    let mut executable = false;
+    let start = page.start_address().as_u64();
+    for segment in kernel_segments() {
+        let segment_start = segment.virt_addr & !(Page::<Size4KiB>::SIZE - 1);
+        if start >= segment_start && start < segment.virt_addr + segment.memory_size {
+            writable |= segment.flags & PF_W != 0;
+            executable |= segment.flags & PF_X != 0;
+        }
+    }
+
+    let mut flags = PageTableFlags::PRESENT;
+    if writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !executable {
+        flags |= no_execute();
+    }
+    flags
+}
+
+/*
+Remaps every page of the kernel image with the permissions of its ELF segment
+
+Unsafe because `mapper` must be the active page table, which has to map the kernel image with
+4 KiB pages.
+ */
+pub unsafe fn protect_kernel_image(
+    mapper: &mut impl Mapper<Size4KiB>,
+) -> Result<(), FlagUpdateError> {
+    for segment in kernel_segments() {
+        for page in segment_pages(segment) {
+            mapper.update_flags(page, kernel_page_flags(page))?.flush();
+        }
+    }
+    Ok(())
+}
+
+// Whether the address lies in an executable segment of the kernel image
+pub fn is_kernel_text(addr: VirtAddr) -> bool {
+    kernel_segments().any(|segment| {
+        segment.flags & PF_X != 0
+            && addr.as_u64() >= segment.virt_addr
+            && addr.as_u64() < segment.virt_addr + segment.memory_size
+    })
+}
@@ -0,0 +1,123 @@
+use super::frames::PageTableFrames;
+use super::vma::{VmaKind, KERNEL_VMAS};
+use super::{protect, with_kernel_memory};
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+// A kernel stack with an unmapped guard page directly below it, so running off its end
+// page-faults instead of corrupting whatever lies below.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStack {
+    guard_page: Page,
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    // Initial stack pointer, the stack grows down from here
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    // Lowest mapped address of the stack
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    pub fn guard_page(&self) -> Page {
+        self.guard_page
+    }
+}
+
+// Maps a new stack of `pages` pages below an unmapped guard page. If mapping fails, the pages
+// mapped so far and the stack's area are released again.
+pub fn alloc_kernel_stack(
+    pages: u64,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<KernelStack, MapToError<Size4KiB>> {
+    // every stack gets its own area including the guard page, so the guard page is never
+    // handed out to anything else
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | protect::no_execute();
+    let area_start = KERNEL_VMAS
+        .lock()
+        .reserve((pages + 1) * PAGE_SIZE, PAGE_SIZE, VmaKind::KernelStack, flags)
+        .map_err(|_| MapToError::FrameAllocationFailed)?;
+
+    // the first page of the area stays unmapped and acts as the guard page
+    let guard_page = Page::containing_address(area_start);
+    let stack = KernelStack {
+        guard_page,
+        bottom: (guard_page + 1).start_address(),
+        top: (guard_page + pages).start_address() + PAGE_SIZE,
+    };
+    for page in Page::range_inclusive(guard_page + 1, guard_page + pages) {
+        if let Err(err) = map_stack_page(page, flags, mapper, frame_allocator) {
+            unsafe { free_kernel_stack(stack, mapper, frame_allocator) };
+            return Err(err);
+        }
+    }
+    Ok(stack)
+}
+
+fn map_stack_page(
+    page: Page,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let result =
+        unsafe { mapper.map_to(page, frame, flags, &mut PageTableFrames(frame_allocator)) };
+    match result {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(err)
+        }
+    }
+}
+
+/*
+Unmaps the stack, gives its frames back to `frame_deallocator` and releases its area
+
+Unsafe because the caller must guarantee that nothing runs on the stack or points into it anymore,
+and that its frames were taken from `frame_deallocator`.
+ */
+pub unsafe fn free_kernel_stack(
+    stack: KernelStack,
+    mapper: &mut OffsetPageTable,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    KERNEL_VMAS
+        .lock()
+        .unmap(stack.guard_page.start_address(), mapper, frame_deallocator)
+        .expect("kernel stack area isn't reserved");
+}
+
+// Same as `alloc_kernel_stack`, using the kernel mapper and frame allocator
+pub fn alloc_stack(pages: u64) -> Option<KernelStack> {
+    with_kernel_memory(|kernel| {
+        alloc_kernel_stack(pages, &mut kernel.mapper, &mut kernel.frame_allocator).ok()
+    })?
+}
+
+// Same as `free_kernel_stack`, using the kernel mapper and frame allocator
+pub unsafe fn free_stack(stack: KernelStack) {
+    with_kernel_memory(|kernel| {
+        free_kernel_stack(stack, &mut kernel.mapper, &mut kernel.frame_allocator)
+    })
+    .expect("kernel memory not installed")
+}
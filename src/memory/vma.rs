@@ -0,0 +1,333 @@
+use super::frames::PageTableFrames;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, page::PageRangeInclusive, FrameAllocator, FrameDeallocator, Mapper,
+        OffsetPageTable, Page, PageTable, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+const PAGE_SIZE: u64 = 4096;
+// Maximum number of areas one manager keeps track of. The table has a fixed size so that the
+// heap itself can be reserved through it before any allocation is possible.
+const MAX_AREAS: usize = 128;
+
+// The kernel hands out virtual ranges from the upper half, leaving out the last P4 entry
+pub const KERNEL_SPACE_START: u64 = 0xffff_8000_0000_0000;
+pub const KERNEL_SPACE_END: u64 = 0xffff_ff80_0000_0000;
+
+// Virtual memory areas of the kernel
+pub static KERNEL_VMAS: Mutex<VirtualMemoryManager> =
+    Mutex::new(VirtualMemoryManager::new(KERNEL_SPACE_START, KERNEL_SPACE_END));
+
+// What a virtual memory area is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    Heap,
+    KernelStack,
+    Mmio,
+    User,
+    // Backed on first access by `memory::demand`
+    DemandPaged,
+    // Already in use when the kernel took over, e.g. mapped by the bootloader
+    Reserved,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+    pub kind: VmaKind,
+    // Pages are only backed by frames once they are first accessed, see `memory::demand`
+    pub demand_paged: bool,
+}
+
+impl Vma {
+    // First address behind the area
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+
+    pub fn pages(&self) -> PageRangeInclusive {
+        Page::range_inclusive(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end() - 1u64),
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum VmaError {
+    // The requested range overlaps an existing area
+    Overlap,
+    // No free range of the requested size is left
+    OutOfSpace,
+    TooManyAreas,
+    NotFound,
+    MapFailed(MapToError<Size4KiB>),
+}
+
+// Keeps track of the reserved and mapped areas of a virtual address range and hands out
+// free ranges from it. The areas are kept sorted by their start address.
+pub struct VirtualMemoryManager {
+    window_start: u64,
+    window_end: u64,
+    areas: [Option<Vma>; MAX_AREAS],
+    len: usize,
+}
+
+impl VirtualMemoryManager {
+    // Creates a manager handing out ranges from `window_start..window_end`
+    pub const fn new(window_start: u64, window_end: u64) -> Self {
+        VirtualMemoryManager {
+            window_start,
+            window_end,
+            areas: [None; MAX_AREAS],
+            len: 0,
+        }
+    }
+
+    // Reserves the part of every P4 entry inside the window that is already in use, so that none
+    // of the mappings set up before the manager existed get handed out again
+    pub fn reserve_used_entries(&mut self, level_4_table: &PageTable) -> Result<(), VmaError> {
+        for (index, entry) in level_4_table.iter().enumerate() {
+            let start = p4_entry_start(index);
+            let last = start + ((1u64 << 39) - 1);
+            if entry.is_unused() || last < self.window_start || start >= self.window_end {
+                continue;
+            }
+            let start = start.max(self.window_start);
+            let end = last.min(self.window_end - 1) + 1;
+            let flags = entry.flags();
+            self.reserve_at(VirtAddr::new(start), end - start, VmaKind::Reserved, flags)?;
+        }
+        Ok(())
+    }
+
+    // Reserves a free range of at least `size` bytes aligned to `align` (at least a page)
+    // Returns the start address of the range
+    pub fn reserve(
+        &mut self,
+        size: u64,
+        align: u64,
+        kind: VmaKind,
+        flags: PageTableFlags,
+    ) -> Result<VirtAddr, VmaError> {
+        self.reserve_area(size, align, kind, flags, false)
+    }
+
+    // Same as `reserve`, but the page fault handler maps the pages of the range on first access
+    pub fn reserve_demand_paged(
+        &mut self,
+        size: u64,
+        align: u64,
+        kind: VmaKind,
+        flags: PageTableFlags,
+    ) -> Result<VirtAddr, VmaError> {
+        self.reserve_area(size, align, kind, flags, true)
+    }
+
+    fn reserve_area(
+        &mut self,
+        size: u64,
+        align: u64,
+        kind: VmaKind,
+        flags: PageTableFlags,
+        demand_paged: bool,
+    ) -> Result<VirtAddr, VmaError> {
+        let size = align_up(size, PAGE_SIZE);
+        let align = align.max(PAGE_SIZE);
+
+        // first fit: look at the gap in front of every area and behind the last one
+        let mut gap_start = self.window_start;
+        for index in 0..=self.len {
+            let gap_end = match self.areas[..self.len].get(index) {
+                Some(area) => area.unwrap().start.as_u64(),
+                None => self.window_end,
+            };
+            let start = align_up(gap_start, align);
+            if start.checked_add(size).map_or(false, |end| end <= gap_end) {
+                self.insert(
+                    index,
+                    Vma {
+                        start: VirtAddr::new(start),
+                        size,
+                        flags,
+                        kind,
+                        demand_paged,
+                    },
+                )?;
+                return Ok(VirtAddr::new(start));
+            }
+            if let Some(area) = self.areas[..self.len].get(index) {
+                gap_start = gap_start.max(area.unwrap().end().as_u64());
+            }
+        }
+        Err(VmaError::OutOfSpace)
+    }
+
+    // Reserves the given range, which may also lie outside the window
+    pub fn reserve_at(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        kind: VmaKind,
+        flags: PageTableFlags,
+    ) -> Result<(), VmaError> {
+        let area = Vma {
+            start: start.align_down(PAGE_SIZE),
+            size: align_up(size + (start.as_u64() % PAGE_SIZE), PAGE_SIZE),
+            flags,
+            kind,
+            demand_paged: false,
+        };
+        let index = self.areas().take_while(|a| a.start < area.start).count();
+        let overlaps_prev = index > 0 && self.areas[index - 1].unwrap().end() > area.start;
+        let overlaps_next = index < self.len && self.areas[index].unwrap().start < area.end();
+        if overlaps_prev || overlaps_next {
+            return Err(VmaError::Overlap);
+        }
+        self.insert(index, area)
+    }
+
+    // Forgets the area starting at `start` without touching its mappings
+    pub fn release(&mut self, start: VirtAddr) -> Result<Vma, VmaError> {
+        let index = self
+            .areas()
+            .position(|a| a.start == start)
+            .ok_or(VmaError::NotFound)?;
+        let area = self.areas[index].take().unwrap();
+        self.areas[index..self.len].rotate_left(1);
+        self.len -= 1;
+        Ok(area)
+    }
+
+    // Returns the area containing the address
+    pub fn find(&self, addr: VirtAddr) -> Option<Vma> {
+        self.areas().find(|a| a.contains(addr))
+    }
+
+    pub fn areas(&self) -> impl Iterator<Item = Vma> + '_ {
+        self.areas[..self.len].iter().map(|a| a.unwrap())
+    }
+
+    // Backs every page of the area starting at `start` with a fresh frame, using the area's flags
+    pub fn map(
+        &self,
+        start: VirtAddr,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), VmaError> {
+        let area = self
+            .areas()
+            .find(|a| a.start == start)
+            .ok_or(VmaError::NotFound)?;
+        for page in area.pages() {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(VmaError::MapFailed(MapToError::FrameAllocationFailed))?;
+            unsafe {
+                mapper
+                    .map_to(page, frame, area.flags, &mut PageTableFrames(frame_allocator))
+                    .map_err(VmaError::MapFailed)?
+                    .flush()
+            };
+        }
+        Ok(())
+    }
+
+    /*
+    Unmaps the area starting at `start`, gives its frames and empty page tables back and releases
+    the area, see `memory::unmap_range`
+
+    Unsafe because the caller must guarantee that nothing uses the area anymore, that its frames
+    were taken from `frame_deallocator` and that `mapper` is the active page table.
+     */
+    pub unsafe fn unmap(
+        &mut self,
+        start: VirtAddr,
+        mapper: &mut OffsetPageTable,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) -> Result<Vma, VmaError> {
+        let area = self.find(start).filter(|a| a.start == start).ok_or(VmaError::NotFound)?;
+        super::unmap_range(mapper, area.pages(), frame_deallocator)
+            .expect("huge page crosses the area boundary");
+        self.release(start)
+    }
+
+    fn insert(&mut self, index: usize, area: Vma) -> Result<(), VmaError> {
+        if self.len == MAX_AREAS {
+            return Err(VmaError::TooManyAreas);
+        }
+        self.areas[self.len] = Some(area);
+        self.areas[index..=self.len].rotate_right(1);
+        self.len += 1;
+        Ok(())
+    }
+}
+
+// Start address of the region covered by the given P4 entry, sign extended for the upper half
+pub(super) fn p4_entry_start(index: usize) -> u64 {
+    let addr = (index as u64) << 39;
+    if index >= 256 {
+        addr | 0xffff_0000_0000_0000
+    } else {
+        addr
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+#[test_case]
+fn test_reserve_first_fit() {
+    let flags = PageTableFlags::PRESENT;
+    let mut vmas = VirtualMemoryManager::new(0x10_0000, 0x20_0000);
+    let a = vmas.reserve(0x1000, 0x1000, VmaKind::Heap, flags).unwrap();
+    let b = vmas.reserve(0x1800, 0x1000, VmaKind::Heap, flags).unwrap();
+    assert_eq!(a.as_u64(), 0x10_0000);
+    assert_eq!(b.as_u64(), 0x10_1000);
+    assert_eq!(vmas.find(b + 0x1fffu64).unwrap().size, 0x2000);
+
+    // the gap left by `a` gets reused, larger alignments skip ahead
+    vmas.release(a).unwrap();
+    assert_eq!(vmas.reserve(0x1000, 0x1000, VmaKind::Mmio, flags).unwrap(), a);
+    let c = vmas.reserve(0x1000, 0x1_0000, VmaKind::Mmio, flags).unwrap();
+    assert_eq!(c.as_u64(), 0x11_0000);
+}
+
+#[test_case]
+fn test_reserve_rejects_overlap_and_exhaustion() {
+    let flags = PageTableFlags::PRESENT;
+    let mut vmas = VirtualMemoryManager::new(0x10_0000, 0x20_0000);
+    vmas.reserve_at(VirtAddr::new(0x18_0000), 0x1000, VmaKind::Reserved, flags)
+        .unwrap();
+    assert!(matches!(
+        vmas.reserve_at(VirtAddr::new(0x17_f000), 0x2000, VmaKind::Reserved, flags),
+        Err(VmaError::Overlap)
+    ));
+    assert!(matches!(
+        vmas.reserve(0x10_0000, 0x1000, VmaKind::Heap, flags),
+        Err(VmaError::OutOfSpace)
+    ));
+}
+
+#[test_case]
+fn test_reserve_used_entries_clips_to_window() {
+    use x86_64::PhysAddr;
+
+    let mut table = PageTable::new();
+    table[0].set_addr(PhysAddr::new(0x1000), PageTableFlags::PRESENT);
+    let mut vmas = VirtualMemoryManager::new(0x1000, 0x0000_8000_0000_0000);
+    vmas.reserve_used_entries(&table).unwrap();
+    let area = vmas.find(VirtAddr::new(0x1000)).unwrap();
+    assert_eq!(area.kind, VmaKind::Reserved);
+    assert_eq!(area.end().as_u64(), 1 << 39);
+}
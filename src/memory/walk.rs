@@ -0,0 +1,241 @@
+// Page table walker for debugging: `translate` shows how the active page table maps a single
+// address, `dump_page_tables` prints all mappings over serial in the style of Linux's ptdump.
+use super::{phys_to_virt, vma};
+use crate::serial_print;
+use core::fmt;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PageTableIndex},
+    PhysAddr, VirtAddr,
+};
+
+// An entry on the way from CR3 to a page
+#[derive(Debug, Clone, Copy)]
+pub struct LevelEntry {
+    // 4 for the P4 table down to 1 for P1 tables
+    pub level: u8,
+    // Physical address of the table holding the entry
+    pub table: PhysAddr,
+    pub index: PageTableIndex,
+    pub flags: PageTableFlags,
+    pub addr: PhysAddr,
+}
+
+// Result of `translate`
+#[derive(Debug, Clone, Copy)]
+pub struct Translation {
+    pub addr: VirtAddr,
+    // The entries walked through, starting at the P4 entry. The walk stops at the first entry that
+    // isn't present or maps a huge page.
+    pub entries: [Option<LevelEntry>; 4],
+    // Physical address `addr` maps to, None if it isn't mapped
+    pub phys: Option<PhysAddr>,
+    pub page_size: Option<u64>,
+}
+
+impl fmt::Display for Translation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "translation of {:#x}:", self.addr.as_u64())?;
+        for entry in self.entries.iter().flatten() {
+            writeln!(
+                f,
+                "  P{} table {:#x} [{:3}]: {:#x} {:?}",
+                entry.level,
+                entry.table.as_u64(),
+                u16::from(entry.index),
+                entry.addr.as_u64(),
+                entry.flags
+            )?;
+        }
+        match (self.phys, self.page_size) {
+            (Some(phys), Some(page_size)) => {
+                let (size, unit) = human_size(page_size);
+                write!(f, "  -> {:#x} ({}{} page)", phys.as_u64(), size, unit)
+            }
+            _ => write!(f, "  -> not mapped"),
+        }
+    }
+}
+
+// Reports every entry the active page table uses to map `addr`
+pub fn translate(addr: VirtAddr) -> Translation {
+    let mut translation = Translation {
+        addr,
+        entries: [None; 4],
+        phys: None,
+        page_size: None,
+    };
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut table_addr = Cr3::read().0.start_address();
+
+    for (i, &index) in indices.iter().enumerate() {
+        let level = 4 - i as u8;
+        let table = unsafe { table_at(table_addr) };
+        let entry = &table[index];
+        translation.entries[i] = Some(LevelEntry {
+            level,
+            table: table_addr,
+            index,
+            flags: entry.flags(),
+            addr: entry.addr(),
+        });
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            break;
+        }
+        let page_size = entry_size(level);
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            translation.phys = Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            translation.page_size = Some(page_size);
+            break;
+        }
+        table_addr = entry.addr();
+    }
+    translation
+}
+
+// A range of consecutive pages of the same size with the same effective flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub page_size: u64,
+    // Flags as seen by an access: writable and user accessible only if every level allows it,
+    // no-execute if any level forbids execution
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    // First address behind the range, 0 for a range reaching the end of the address space
+    pub fn end(&self) -> u64 {
+        self.start.as_u64().wrapping_add(self.size)
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag, name| if self.flags.contains(flag) { name } else { "" };
+        let (size, size_unit) = human_size(self.size);
+        let (page_size, page_unit) = human_size(self.page_size);
+        write!(
+            f,
+            "{:#018x}-{:#018x} {:>7}{:1} {:>3}{:1}  {:3} {:2} {:2} {:3} {:3} {:3}",
+            self.start.as_u64(),
+            self.end(),
+            size,
+            size_unit,
+            page_size,
+            page_unit,
+            flag(PageTableFlags::USER_ACCESSIBLE, "USR"),
+            flag(PageTableFlags::WRITABLE, "RW"),
+            flag(PageTableFlags::NO_EXECUTE, "NX"),
+            flag(PageTableFlags::WRITE_THROUGH, "PWT"),
+            flag(PageTableFlags::NO_CACHE, "PCD"),
+            flag(PageTableFlags::GLOBAL, "GLB"),
+        )
+    }
+}
+
+// Calls `f` for every mapped range of the active page table, in address order
+pub fn for_each_mapping(mut f: impl FnMut(&MappedRange)) {
+    let level_4_table = Cr3::read().0.start_address();
+    let mut current: Option<MappedRange> = None;
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    unsafe { walk_table(level_4_table, 4, 0, inherited, &mut current, &mut f) };
+    if let Some(range) = current {
+        f(&range);
+    }
+}
+
+// Prints all mappings of the active page table over serial, merging consecutive pages with the
+// same flags
+pub fn dump_page_tables() {
+    serial_print!("{:^37} {:>8} {:>4}  flags\n", "range", "size", "page");
+    for_each_mapping(|range| serial_print!("{}\n", range));
+}
+
+// Walks a table of the given level whose first entry maps `base`. `inherited` holds the
+// writable and user bits of the levels above, plus no-execute if any of them set it.
+unsafe fn walk_table(
+    table_addr: PhysAddr,
+    level: u8,
+    base: u64,
+    inherited: PageTableFlags,
+    current: &mut Option<MappedRange>,
+    f: &mut impl FnMut(&MappedRange),
+) {
+    let table = table_at(table_addr);
+    let size = entry_size(level);
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = if level == 4 {
+            vma::p4_entry_start(index)
+        } else {
+            base + index as u64 * size
+        };
+        let combined = combine(inherited, flags);
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let caching = PageTableFlags::WRITE_THROUGH
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::GLOBAL;
+            let leaf_flags = combined | (flags & caching);
+            extend(
+                current,
+                MappedRange {
+                    start: VirtAddr::new(start),
+                    size,
+                    page_size: size,
+                    flags: leaf_flags,
+                },
+                f,
+            );
+        } else {
+            walk_table(entry.addr(), level - 1, start, combined, current, f);
+        }
+    }
+}
+
+// Flags of an access after passing through an entry with the given flags
+fn combine(inherited: PageTableFlags, flags: PageTableFlags) -> PageTableFlags {
+    let permissions = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    (inherited & flags & permissions) | ((inherited | flags) & PageTableFlags::NO_EXECUTE)
+}
+
+// Appends the page to the current range if it continues it, otherwise reports the current range
+// and starts a new one
+fn extend(
+    current: &mut Option<MappedRange>,
+    page: MappedRange,
+    f: &mut impl FnMut(&MappedRange),
+) {
+    if let Some(range) = current {
+        if range.end() == page.start.as_u64()
+            && range.page_size == page.page_size
+            && range.flags == page.flags
+        {
+            range.size += page.size;
+            return;
+        }
+        f(range);
+    }
+    *current = Some(page);
+}
+
+// Number of bytes an entry of a table of the given level maps
+fn entry_size(level: u8) -> u64 {
+    1 << (12 + 9 * (level as u64 - 1))
+}
+
+unsafe fn table_at(addr: PhysAddr) -> &'static PageTable {
+    &*phys_to_virt(addr).as_ptr()
+}
+
+// Splits a size in bytes into a number and the largest binary unit that divides it
+fn human_size(size: u64) -> (u64, &'static str) {
+    [(30, "G"), (20, "M"), (10, "K")]
+        .iter()
+        .find(|&&(shift, _)| size >= 1 << shift && size % (1 << shift) == 0)
+        .map_or((size, ""), |&(shift, unit)| (size >> shift, unit))
+}
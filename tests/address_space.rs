@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(asm)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+	self, buddy::BuddyFrameAllocator,
+	address_space::{self, AddressSpace, AddressSpaceError},
+	vma::{VmaKind, KERNEL_VMAS},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let mapper = unsafe {memory::init(phys_mem_offset)};
+	let frame_allocator = unsafe {
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+	};
+	memory::install(mapper, frame_allocator);
+
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+	memory::with_kernel_memory(|kernel| kernel.frame_allocator.free_frames()).unwrap()
+}
+
+// Reserves and maps `size` bytes of the address space's user half
+fn map_user(space: &mut AddressSpace, size: u64) -> VirtAddr {
+	let start = space.reserve(size, PageTableFlags::WRITABLE).unwrap();
+	space.map(start).unwrap();
+	start
+}
+
+#[test_case]
+fn user_halves_are_isolated(){
+	let mut a = AddressSpace::new().unwrap();
+	let mut b = AddressSpace::new().unwrap();
+	let addr = map_user(&mut a, 4096);
+	assert_eq!(map_user(&mut b, 4096), addr);
+	let ptr: *mut u64 = addr.as_mut_ptr();
+
+	a.activate();
+	unsafe { ptr.write_volatile(1) };
+	b.activate();
+	unsafe { ptr.write_volatile(2) };
+	a.activate();
+	assert_eq!(unsafe { ptr.read_volatile() }, 1);
+	b.activate();
+	assert_eq!(unsafe { ptr.read_volatile() }, 2);
+
+	address_space::activate_kernel();
+	assert!(memory::translate(addr).phys.is_none());
+}
+
+#[test_case]
+fn kernel_mappings_are_shared(){
+	let mut space = AddressSpace::new().unwrap();
+
+	// mapped after the address space was created, in a P4 entry nothing used before
+	let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+	let mut vmas = KERNEL_VMAS.lock();
+	let start = vmas.reserve(4096, 1 << 39, VmaKind::Reserved, flags).unwrap();
+	memory::with_kernel_memory(|kernel| {
+		vmas.map(start, &mut kernel.mapper, &mut kernel.frame_allocator)
+	}).unwrap().unwrap();
+	drop(vmas);
+	unsafe { start.as_mut_ptr::<u64>().write_volatile(7) };
+
+	space.activate();
+	assert_eq!(unsafe { start.as_ptr::<u64>().read_volatile() }, 7);
+	address_space::activate_kernel();
+}
+
+#[test_case]
+fn kernel_entries_cannot_be_mapped(){
+	let mut space = AddressSpace::new().unwrap();
+	let code = Page::containing_address(VirtAddr::new(main as *const () as u64));
+	let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
+	assert!(matches!(
+		unsafe { space.map_to(code, frame) },
+		Err(AddressSpaceError::NotUserAddress(_))
+	));
+}
+
+#[test_case]
+fn dropping_frees_all_frames(){
+	let free = free_frames();
+	{
+		let mut space = AddressSpace::new().unwrap();
+		let addr = map_user(&mut space, 16 * 4096);
+		space.activate();
+		unsafe { addr.as_mut_ptr::<u64>().write_volatile(3) };
+		// dropped while active, which switches back to the kernel's page table
+	}
+	assert_eq!(free_frames(), free);
+}
+
+#[test_case]
+fn freeing_tables_keeps_the_pcid(){
+	let mut space = AddressSpace::new().unwrap();
+	let addr = map_user(&mut space, 4096);
+	space.activate();
+	// the last page of its P1 table, so unmapping it frees tables and flushes the TLB
+	space.unmap(addr).unwrap();
+
+	let cr3: u64;
+	unsafe { asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags)) };
+	assert_eq!(cr3 & 0xfff, u64::from(space.pcid().unwrap_or(0)));
+	address_space::activate_kernel();
+}
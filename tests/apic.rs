@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::acpi;
+use blog_os::interrupts::{self, apic, InterruptController};
+use blog_os::memory::{self, buddy::BuddyFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let mapper = unsafe {memory::init(phys_mem_offset)};
+	let frame_allocator = unsafe {
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+	};
+	memory::install(mapper, frame_allocator);
+
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+fn wait_for_ticks(count: u64) {
+	let start = interrupts::ticks();
+	while interrupts::ticks() < start + count {
+		x86_64::instructions::hlt();
+	}
+}
+
+#[test_case]
+fn madt_describes_the_interrupt_controllers(){
+	let madt = acpi::madt().expect("no MADT");
+	assert_eq!(madt.local_apic.as_u64(), 0xfee0_0000);
+	assert!(madt.cpus >= 1);
+	let io_apic = madt.io_apics().next().expect("no I/O APIC");
+	assert_eq!(io_apic.gsi_base, 0);
+
+	// QEMU connects the PIT to GSI 2, which therefore isn't IRQ 2
+	assert_eq!(madt.isa_irq(0).unwrap().gsi, 2);
+	assert!(madt.isa_irq(2).is_none());
+	assert_eq!(madt.isa_irq(1).unwrap().gsi, 1);
+}
+
+#[test_case]
+fn timer_runs_on_the_pics(){
+	assert_eq!(interrupts::controller(), InterruptController::Pic);
+	wait_for_ticks(2);
+}
+
+#[test_case]
+fn timer_runs_on_the_apics(){
+	assert_eq!(interrupts::init_apic(), InterruptController::Apic);
+	assert_eq!(interrupts::controller(), InterruptController::Apic);
+	assert!(apic::local_apic_id().is_some());
+	wait_for_ticks(2);
+
+	// masking the timer's IRQ stops the ticks, the PIT fires every 55 ms
+	apic::set_irq_masked(0, true).unwrap();
+	let ticks = interrupts::ticks();
+	for _ in 0..20_000_000 {
+		core::sync::atomic::spin_loop_hint();
+	}
+	assert_eq!(interrupts::ticks(), ticks);
+	apic::set_irq_masked(0, false).unwrap();
+	wait_for_ticks(2);
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::backtrace::Backtrace;
+use blog_os::memory::{self, buddy::BuddyFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let mapper = unsafe {memory::init(phys_mem_offset)};
+	let frame_allocator = unsafe {
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+	};
+	memory::install(mapper, frame_allocator);
+
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+#[inline(never)]
+fn innermost() -> [u64; 3] {
+	let mut frames = [0; 3];
+	for (slot, addr) in frames.iter_mut().zip(Backtrace::current().frames()) {
+		*slot = addr;
+	}
+	frames
+}
+
+// The volatile reads keep the calls from becoming tail calls, which would leave no frame behind
+#[inline(never)]
+fn outer() -> [u64; 3] {
+	let frames = innermost();
+	unsafe { core::ptr::read_volatile(&frames) }
+}
+
+#[inline(never)]
+fn outermost() -> [u64; 3] {
+	let frames = outer();
+	unsafe { core::ptr::read_volatile(&frames) }
+}
+
+#[test_case]
+fn frames_lead_through_the_callers(){
+	let frames = outermost();
+	// the calls lie within the first few hundred bytes of the callers
+	let within = |addr: u64, function: u64| addr > function && addr < function + 0x200;
+	assert!(within(frames[0], innermost as u64), "{:#x?}", frames);
+	assert!(within(frames[1], outer as u64), "{:#x?}", frames);
+	assert!(within(frames[2], outermost as u64), "{:#x?}", frames);
+}
+
+#[test_case]
+fn unmapped_frames_end_the_walk(){
+	// page 0 is never mapped, 0x8000_0000_0000 isn't even canonical
+	let backtrace = Backtrace::from_registers(0x1234, 0x10);
+	let mut frames = backtrace.frames();
+	assert_eq!(frames.next(), Some(0x1234));
+	assert_eq!(frames.next(), None);
+	assert_eq!(Backtrace::from_registers(0x1234, 0x8000_0000_0000).frames().count(), 1);
+}
@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+	self, buddy::BuddyFrameAllocator, cow, frames,
+	vma::{VmaKind, KERNEL_VMAS},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let mapper = unsafe {memory::init(phys_mem_offset)};
+	let frame_allocator = unsafe {
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+	};
+	memory::install(mapper, frame_allocator);
+
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+// Reserves a page of kernel address space, backed by a frame if `mapped` is set
+fn reserve_page(mapped: bool) -> Page {
+	let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+	let mut vmas = KERNEL_VMAS.lock();
+	let start = vmas.reserve(4096, 4096, VmaKind::Reserved, flags).unwrap();
+	if mapped {
+		memory::with_kernel_memory(|kernel| {
+			vmas.map(start, &mut kernel.mapper, &mut kernel.frame_allocator)
+		}).unwrap().unwrap();
+	}
+	Page::containing_address(start)
+}
+
+fn frame_of(page: Page) -> PhysFrame {
+	memory::with_kernel_memory(|kernel| kernel.mapper.translate_page(page)).unwrap().unwrap()
+}
+
+#[test_case]
+fn write_copies_shared_page(){
+	let src = reserve_page(true);
+	let dst = reserve_page(false);
+	let src_ptr: *mut u64 = src.start_address().as_mut_ptr();
+	let dst_ptr: *mut u64 = dst.start_address().as_mut_ptr();
+	unsafe { src_ptr.write_volatile(1) };
+
+	cow::share(src, dst).unwrap();
+	let frame = frame_of(src);
+	assert_eq!(frame_of(dst), frame);
+	assert_eq!(frames::refcount(frame), 2);
+	unsafe { assert_eq!(dst_ptr.read_volatile(), 1) };
+
+	// the writer gets its own copy, the other mapping keeps the old contents
+	unsafe { dst_ptr.write_volatile(2) };
+	assert_ne!(frame_of(dst), frame);
+	assert_eq!(frames::refcount(frame), 1);
+	unsafe {
+		assert_eq!(src_ptr.read_volatile(), 1);
+		assert_eq!(dst_ptr.read_volatile(), 2);
+	}
+
+	// the last mapping of the frame is made writable again without copying
+	unsafe { src_ptr.write_volatile(3) };
+	assert_eq!(frame_of(src), frame);
+	unsafe {
+		assert_eq!(src_ptr.read_volatile(), 3);
+		assert_eq!(dst_ptr.read_volatile(), 2);
+	}
+}
+
+#[test_case]
+fn destination_must_be_reserved(){
+	let src = reserve_page(true);
+	// the lower half belongs to user address spaces, the kernel never reserves anything there
+	let dst = Page::containing_address(VirtAddr::new(0x1000_0000_0000));
+	let frame = frame_of(src);
+	assert!(matches!(cow::share(src, dst), Err(cow::CowError::DestinationNotReserved)));
+	assert_eq!(frames::refcount(frame), 1);
+	assert!(memory::translate(dst.start_address()).phys.is_none());
+}
@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{self, buddy::BuddyFrameAllocator, demand, vma::{VmaKind, KERNEL_VMAS}};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let mapper = unsafe {memory::init(phys_mem_offset)};
+	let frame_allocator = unsafe {
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+	};
+	memory::install(mapper, frame_allocator);
+
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+	memory::with_kernel_memory(|kernel| kernel.frame_allocator.free_frames()).unwrap()
+}
+
+#[test_case]
+fn pages_are_backed_on_first_access(){
+	let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+	// a gigabyte costs nothing as long as it isn't touched
+	let free = free_frames();
+	let start = demand::reserve_lazy(1 << 30, flags).unwrap();
+	assert_eq!(free_frames(), free);
+	assert_eq!(KERNEL_VMAS.lock().find(start).unwrap().kind, VmaKind::DemandPaged);
+
+	let ptr: *mut u64 = start.as_mut_ptr();
+	unsafe {
+		// fresh pages read as zero
+		assert_eq!(ptr.read_volatile(), 0);
+		ptr.write_volatile(42);
+		let far = ptr.add((512 << 20) / 8);
+		far.write_volatile(13);
+		assert_eq!(ptr.read_volatile(), 42);
+		assert_eq!(far.read_volatile(), 13);
+	}
+	assert!(free_frames() < free);
+}
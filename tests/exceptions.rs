@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(asm)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::interrupts::exceptions::{
+	self, DescriptorTable, ErrorCode, Exception, ExceptionInfo, HookAction,
+};
+use blog_os::memory::{self, buddy::BuddyFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::instructions::segmentation;
+use x86_64::structures::gdt::SegmentSelector;
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+// The last exception the hook saw
+static LAST: Mutex<Option<ExceptionInfo>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let mapper = unsafe {memory::init(phys_mem_offset)};
+	let frame_allocator = unsafe {
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+	};
+	memory::install(mapper, frame_allocator);
+
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+fn record(info: &ExceptionInfo) -> HookAction {
+	*LAST.lock() = Some(*info);
+	HookAction::Retry
+}
+
+// Records the exception and skips the `mov ds, ax` that caused it, with or without an operand size
+// prefix
+fn record_and_skip_segment_load(info: &ExceptionInfo) -> HookAction {
+	*LAST.lock() = Some(*info);
+	match info.instruction_bytes() {
+		[0x66, 0x8e, ..] => HookAction::Skip(3),
+		[0x8e, ..] => HookAction::Skip(2),
+		_ => HookAction::Report,
+	}
+}
+
+fn take_last() -> ExceptionInfo {
+	LAST.lock().take().expect("no exception was raised")
+}
+
+#[test_case]
+fn breakpoint_reaches_the_hook(){
+	exceptions::set_hook(Some(record));
+	x86_64::instructions::interrupts::int3();
+	exceptions::set_hook(None);
+
+	let info = take_last();
+	assert_eq!(info.exception, Exception::Breakpoint);
+	assert_eq!(info.error_code, None);
+	// a trap, so the instruction pointer is already behind the int3
+	let int3 = info.frame.rip - 1;
+	assert_eq!(unsafe { *(int3 as *const u8) }, 0xcc);
+}
+
+#[test_case]
+fn invalid_selector_is_decoded(){
+	exceptions::set_hook(Some(record_and_skip_segment_load));
+	// far beyond the end of the GDT
+	unsafe { segmentation::load_ds(SegmentSelector::new(0x100, PrivilegeLevel::Ring0)) };
+	exceptions::set_hook(None);
+
+	let info = take_last();
+	assert_eq!(info.exception, Exception::GeneralProtectionFault);
+	match info.error_code {
+		Some(ErrorCode::Selector(selector)) => {
+			assert_eq!(selector.table(), DescriptorTable::Gdt);
+			assert_eq!(selector.index(), 0x100);
+			assert!(!selector.external());
+		}
+		other => panic!("unexpected error code {:?}", other),
+	}
+	assert!(info.instruction_bytes().len() > 2);
+}
+
+#[test_case]
+fn registers_are_saved(){
+	exceptions::set_hook(Some(record));
+	unsafe {
+		asm!("int3", in("rax") 0x1111u64, in("rsi") 0x2222u64, in("r15") 0x3333u64);
+	}
+	exceptions::set_hook(None);
+
+	let info = take_last();
+	assert_eq!(info.frame.vector, 3);
+	assert_eq!((info.frame.rax, info.frame.rsi, info.frame.r15), (0x1111, 0x2222, 0x3333));
+	// interrupts are enabled by `blog_os::init`
+	assert!(info.frame.rflags & (1 << 9) != 0);
+	let cr3 = x86_64::registers::control::Cr3::read().0.start_address().as_u64();
+	assert_eq!(info.control.cr3 & !0xfff, cr3);
+}
@@ -0,0 +1,113 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::buddy::{BuddyFrameAllocator, MAX_ORDER};
+use blog_os::memory::frames::{self, FrameFlags};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::VirtAddr;
+
+static FRAME_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let frame_allocator = unsafe {
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+	};
+	*FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn single_frames_are_reused(){
+	let mut guard = FRAME_ALLOCATOR.lock();
+	let allocator = guard.as_mut().unwrap();
+	let free = allocator.free_frames();
+
+	let frame = allocator.allocate_frame().unwrap();
+	assert_eq!(allocator.free_frames(), free - 1);
+	unsafe { allocator.deallocate_frame(frame) };
+	assert_eq!(allocator.free_frames(), free);
+	assert_eq!(allocator.allocate_frame(), Some(frame));
+	unsafe { allocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn contiguous_blocks_are_aligned(){
+	let mut guard = FRAME_ALLOCATOR.lock();
+	let allocator = guard.as_mut().unwrap();
+
+	for order in 0..=MAX_ORDER {
+		let block = allocator.allocate_contiguous(order).unwrap();
+		assert_eq!(block.start_address().as_u64() % (4096 << order), 0);
+		unsafe { allocator.deallocate_contiguous(block, order) };
+	}
+	assert!(allocator.allocate_contiguous(MAX_ORDER + 1).is_none());
+}
+
+#[test_case]
+fn buddies_merge_on_free(){
+	let mut guard = FRAME_ALLOCATOR.lock();
+	let allocator = guard.as_mut().unwrap();
+	let free = allocator.free_frames();
+
+	// free the two halves of a known max order block separately, they must merge into it again
+	let block = allocator.allocate_contiguous(MAX_ORDER).unwrap();
+	let half = PhysFrame::containing_address(block.start_address() + (4096u64 << (MAX_ORDER - 1)));
+	unsafe {
+		allocator.deallocate_contiguous(half, MAX_ORDER - 1);
+		allocator.deallocate_contiguous(block, MAX_ORDER - 1);
+	}
+	assert_eq!(allocator.free_frames(), free);
+
+	// unmerged halves would sit on the smaller free list, and the block would come from elsewhere
+	let merged: PhysFrame = allocator.allocate_contiguous(MAX_ORDER).unwrap();
+	assert_eq!(merged, block);
+	unsafe { allocator.deallocate_contiguous(merged, MAX_ORDER) };
+}
+
+#[test_case]
+fn frame_database_tracks_references(){
+	let mut guard = FRAME_ALLOCATOR.lock();
+	let allocator = guard.as_mut().unwrap();
+
+	let frame = allocator.allocate_frame().unwrap();
+	assert_eq!(frames::refcount(frame), 1);
+	frames::get(frame);
+	frames::set_flags(frame, FrameFlags::USER | FrameFlags::DMA);
+	assert!(frames::descriptor(frame).unwrap().flags().contains(FrameFlags::DMA));
+	assert!(!frames::put(frame));
+	assert!(frames::put(frame));
+
+	unsafe { allocator.deallocate_frame(frame) };
+	let descriptor = frames::descriptor(frame).unwrap();
+	assert!(descriptor.is_free());
+	assert!(descriptor.flags().is_empty());
+}
+
+#[test_case]
+fn frame_database_covers_memory_map(){
+	let guard = FRAME_ALLOCATOR.lock();
+	let allocator = guard.as_ref().unwrap();
+
+	let free = frames::frames().filter(|(_, d)| d.is_free()).count();
+	assert!(free >= allocator.free_frames());
+	// the kernel image and the boot page tables are pinned
+	assert!(frames::frames().any(|(_, d)| d.flags().contains(FrameFlags::KERNEL | FrameFlags::PINNED)));
+	assert!(frames::frames().any(|(_, d)| d.flags().contains(FrameFlags::PAGE_TABLE)));
+}
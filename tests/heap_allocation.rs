@@ -10,23 +10,24 @@ use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use blog_os::allocator::HEAP_SIZE;
+use blog_os::allocator::{HEAP_MAX_SIZE, HEAP_SIZE};
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
 	use blog_os::allocator;
-	use blog_os::memory::{self, BootInfoFrameAllocator};
+	use blog_os::memory::{self, buddy::BuddyFrameAllocator};
 	use x86_64::VirtAddr;
 
 	blog_os::init();
 	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
 	let mut mapper = unsafe {memory::init(phys_mem_offset)};
 	let mut frame_allocator = unsafe {
-		BootInfoFrameAllocator::init(&boot_info.memory_map)
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
 	};
 	allocator::init_heap(&mut mapper, &mut frame_allocator)
 		.expect("heap initialization failed");
+	memory::install(mapper, frame_allocator);
 
 	test_main();
 	loop{}
@@ -71,4 +72,134 @@ fn many_boxes_long_lived() {
 		assert_eq!(*x, i);
 	}
 	assert_eq!(*long_lived, 1);
-}
\ No newline at end of file
+}
+
+// The bump allocator only reclaims memory once every allocation is freed, so while a long-lived
+// box exists freed memory isn't handed out again and the heap has to grow instead
+#[cfg(feature = "alloc-bump")]
+#[test_case]
+fn bump_reuses_nothing_while_allocations_live() {
+	let long_lived = Box::new(1);
+	let first = Box::new(2);
+	let first_addr = &*first as *const i32 as usize;
+	drop(first);
+	let second = Box::new(3);
+	assert!(&*second as *const i32 as usize > first_addr);
+	assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn heap_grows_beyond_initial_size(){
+	let n = 2 * HEAP_SIZE;
+	let mut vec = Vec::with_capacity(n);
+	for i in 0..n {
+		vec.push(i as u8);
+	}
+	assert_eq!(vec[n - 1], (n - 1) as u8);
+}
+
+#[test_case]
+fn heap_stops_growing_at_limit(){
+	use alloc::alloc::{alloc, Layout};
+
+	use blog_os::memory;
+
+	let free_frames = || {
+		memory::with_kernel_memory(|kernel| kernel.frame_allocator.free_frames()).unwrap()
+	};
+	// `alloc` instead of `Box` so that the failure doesn't end up in `alloc_error_handler`
+	let layout = Layout::from_size_align(2 * HEAP_MAX_SIZE, 8).unwrap();
+	let free = free_frames();
+	assert!(unsafe { alloc(layout) }.is_null());
+	// nothing is mapped for an allocation that can't fit anyway
+	assert_eq!(free_frames(), free);
+}
+
+#[test_case]
+fn stats_track_live_allocations(){
+	use blog_os::allocator::heap_stats;
+
+	let before = heap_stats();
+	let value = Box::new([0u64; 4]);
+	let stats = heap_stats();
+	assert_eq!(stats.live_allocations, before.live_allocations + 1);
+	assert_eq!(stats.allocated_bytes, before.allocated_bytes + 32);
+	assert!(stats.peak_allocated_bytes >= stats.allocated_bytes);
+	assert!(stats.largest_free_block <= stats.free_bytes);
+
+	drop(value);
+	let after = heap_stats();
+	assert_eq!(after.live_allocations, before.live_allocations);
+	assert_eq!(after.allocated_bytes, before.allocated_bytes);
+}
+
+#[cfg(feature = "heap-debug")]
+#[test_case]
+fn leak_check_reports_nothing_after_cleanup(){
+	let check = blog_os::allocator::leak_check();
+	let mut vec = Vec::new();
+	for i in 0..100 {
+		vec.push(Box::new(i));
+	}
+	drop(vec);
+	check.assert_no_leaks();
+}
+
+#[cfg(feature = "heap-debug")]
+#[inline(never)]
+fn allocate_boxed() -> Box<u64> {
+	Box::new(7)
+}
+
+#[cfg(feature = "heap-debug")]
+#[test_case]
+fn leaks_record_their_allocation_site(){
+	let check = blog_os::allocator::leak_check();
+	let leaked = allocate_boxed();
+	let function = allocate_boxed as u64;
+	let mut leaks = 0;
+	check.for_each_leak(|record| {
+		assert!(
+			record.site.iter().any(|&addr| addr > function && addr < function + 0x100),
+			"{:#x?} doesn't lead to {:#x}", record.site, function
+		);
+		leaks += 1;
+	});
+	assert_eq!(leaks, 1);
+	drop(leaked);
+}
+
+#[cfg(feature = "heap-debug")]
+#[test_case]
+fn leak_callback_may_allocate(){
+	use alloc::format;
+
+	let check = blog_os::allocator::leak_check();
+	let leaked = Box::new(5u32);
+	let mut leaks = 0;
+	check.for_each_leak(|record| {
+		assert!(format!("{:?}", record).contains("size"));
+		leaks += 1;
+	});
+	assert_eq!(leaks, 1);
+	drop(leaked);
+}
+
+#[cfg(feature = "heap-guard")]
+#[test_case]
+fn guard_detects_overrun_and_double_free(){
+	use alloc::alloc::{alloc, dealloc, Layout};
+	use blog_os::allocator::heap_errors;
+
+	let layout = Layout::from_size_align(8, 8).unwrap();
+	let errors = heap_errors();
+	unsafe {
+		let ptr = alloc(layout);
+		ptr.add(8).write(0); // one byte past the end
+		dealloc(ptr, layout);
+		assert_eq!(heap_errors(), errors + 1);
+
+		dealloc(ptr, layout);
+		assert_eq!(heap_errors(), errors + 2);
+	}
+}
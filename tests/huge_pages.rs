@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+	self, buddy::BuddyFrameAllocator, huge,
+	vma::{VmaKind, KERNEL_VMAS},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, Size2MiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let mapper = unsafe {memory::init(phys_mem_offset)};
+	let frame_allocator = unsafe {
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+	};
+	memory::install(mapper, frame_allocator);
+
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+fn flags() -> PageTableFlags {
+	PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+}
+
+fn reserve(size: u64, align: u64) -> VirtAddr {
+	KERNEL_VMAS.lock().reserve(size, align, VmaKind::Reserved, flags()).unwrap()
+}
+
+fn free_frames() -> usize {
+	memory::with_kernel_memory(|kernel| kernel.frame_allocator.free_frames()).unwrap()
+}
+
+#[test_case]
+fn aligned_ranges_get_2mib_pages(){
+	let free = free_frames();
+	// start 4 KiB in front of a 2 MiB boundary so the range needs both page sizes
+	let start = reserve(8 << 20, 2 << 20) + ((2u64 << 20) - 4096);
+	let counts = memory::with_kernel_memory(|kernel| {
+		huge::map_backed_range(&mut kernel.mapper, start, (4 << 20) + 4096, flags(), &mut kernel.frame_allocator)
+	}).unwrap();
+	assert_eq!(counts.pages_4kib, 1);
+	assert_eq!(counts.pages_2mib, 2);
+	assert_eq!(counts.bytes(), (4 << 20) + 4096);
+
+	let ptr: *mut u64 = start.as_mut_ptr();
+	unsafe {
+		let last = ptr.add(((4 << 20) + 4096) / 8 - 1);
+		last.write_volatile(42);
+		assert_eq!(last.read_volatile(), 42);
+	}
+	let huge_page = Page::<Size2MiB>::containing_address(start + 4096u64);
+	let translated = memory::with_kernel_memory(|kernel| kernel.mapper.translate_page(huge_page)).unwrap();
+	assert!(translated.is_ok());
+
+	let pages = Page::range_inclusive(
+		Page::containing_address(start),
+		Page::containing_address(start + (4u64 << 20)),
+	);
+	let stats = unsafe { memory::unmap_kernel_range(pages) }.unwrap().unwrap();
+	assert_eq!(stats.pages, 3);
+	assert_eq!(stats.frames_freed, 1025);
+	assert_eq!(free_frames(), free);
+}
+
+#[test_case]
+fn physical_ranges_use_largest_pages(){
+	let size = if huge::supports_1gib_pages() { 1 << 30 } else { 4 << 20 };
+	let start = reserve(size, 1 << 30);
+	let counts = memory::with_kernel_memory(|kernel| unsafe {
+		huge::map_physical_range(&mut kernel.mapper, start, PhysAddr::new(0), size, flags(), &mut kernel.frame_allocator)
+	}).unwrap().unwrap();
+	assert_eq!(counts.pages_4kib, 0);
+	assert_eq!(counts.bytes(), size);
+
+	// the mapping shows the same memory as the bootloader's physical memory mapping
+	let phys = PhysAddr::new(0x20_0000);
+	let direct: *const u64 = memory::phys_to_virt(phys).as_ptr();
+	let mapped: *const u64 = (start + phys.as_u64()).as_ptr();
+	unsafe { assert_eq!(mapped.read_volatile(), direct.read_volatile()) };
+}
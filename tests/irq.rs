@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::interrupts::{self, InterruptContext, IrqError};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+static CALLS: AtomicU64 = AtomicU64::new(0);
+static LAST_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+fn count_calls(context: &InterruptContext) {
+	CALLS.fetch_add(1, Ordering::Relaxed);
+	LAST_VECTOR.store(context.vector, Ordering::Relaxed);
+}
+
+fn wait_for_ticks(count: u64) {
+	let start = interrupts::ticks();
+	while interrupts::ticks() < start + count {
+		x86_64::instructions::hlt();
+	}
+}
+
+#[test_case]
+fn shared_handlers_are_chained(){
+	interrupts::register_irq(0, count_calls).unwrap();
+	wait_for_ticks(3);
+	// the timer's own handler still runs, next to ours
+	assert!(CALLS.load(Ordering::Relaxed) >= 2);
+	assert_eq!(LAST_VECTOR.load(Ordering::Relaxed), interrupts::PIC_1_OFFSET);
+
+	interrupts::unregister_irq(0, count_calls).unwrap();
+	let calls = CALLS.load(Ordering::Relaxed);
+	wait_for_ticks(2);
+	assert_eq!(CALLS.load(Ordering::Relaxed), calls);
+}
+
+#[test_case]
+fn registration_errors(){
+	interrupts::register_irq(5, count_calls).unwrap();
+	assert!(matches!(interrupts::register_irq(5, count_calls), Err(IrqError::AlreadyRegistered)));
+	interrupts::unregister_irq(5, count_calls).unwrap();
+	assert!(matches!(interrupts::unregister_irq(5, count_calls), Err(IrqError::NotRegistered)));
+	assert!(matches!(interrupts::register_irq(223, count_calls), Err(IrqError::InvalidIrq(223))));
+	assert!(matches!(interrupts::mask_irq(40), Err(IrqError::NotMaskable(40))));
+}
+
+#[test_case]
+fn masked_irqs_stay_silent(){
+	wait_for_ticks(1);
+	interrupts::mask_irq(0).unwrap();
+	let ticks = interrupts::ticks();
+	// the PIT fires every 55 ms
+	for _ in 0..20_000_000 {
+		core::sync::atomic::spin_loop_hint();
+	}
+	assert_eq!(interrupts::ticks(), ticks);
+	interrupts::unmask_irq(0).unwrap();
+	wait_for_ticks(2);
+}
@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+	self, buddy::BuddyFrameAllocator, frames,
+	mmio::{ioremap, CacheMode},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let mapper = unsafe {memory::init(phys_mem_offset)};
+	let frame_allocator = unsafe {
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+	};
+	memory::install(mapper, frame_allocator);
+
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+	let page = Page::containing_address(addr);
+	memory::with_kernel_memory(|kernel| kernel.mapper.translate_page(page).is_ok()).unwrap()
+}
+
+#[test_case]
+fn mapping_shows_physical_memory_and_unmaps_on_drop(){
+	let frame = memory::with_kernel_memory(|kernel| kernel.frame_allocator.allocate_frame()).unwrap().unwrap();
+	let phys = frame.start_address() + 0x10u64;
+	let mapping = unsafe { ioremap(phys, 8, CacheMode::WriteBack) }.unwrap();
+	assert_eq!(mapping.virt_addr().as_u64() % 4096, 0x10);
+
+	mapping.write::<u64>(0, 0x1234_5678);
+	let direct: *const u64 = memory::phys_to_virt(phys).as_ptr();
+	unsafe { assert_eq!(direct.read_volatile(), 0x1234_5678) };
+
+	let addr = mapping.virt_addr();
+	drop(mapping);
+	assert!(!is_mapped(addr));
+	// the frame still belongs to us
+	assert_eq!(frames::refcount(frame), 1);
+	memory::with_kernel_memory(|kernel| unsafe { kernel.frame_allocator.deallocate_frame(frame) });
+}
+
+#[test_case]
+fn local_apic_registers_are_readable(){
+	let apic = unsafe { ioremap(PhysAddr::new(0xfee0_0000), 0x400, CacheMode::Uncached) }.unwrap();
+	// the low byte of the version register is 0x1x for integrated APICs
+	let version: u32 = apic.read(0x30);
+	assert_eq!(version & 0xf0, 0x10);
+}
+
+#[test_case]
+fn write_combining_framebuffer(){
+	let vga = unsafe { ioremap(PhysAddr::new(0xb8000), 80 * 25 * 2, CacheMode::WriteCombining) }.unwrap();
+	vga.write::<u16>(0, 0x0f41);
+	assert_eq!(vga.read::<u16>(0), 0x0f41);
+
+	// QEMU has a PAT, so the 4 KiB entry selects PAT entry 4 through bit 7
+	let leaf = memory::translate(vga.virt_addr()).entries[3].unwrap();
+	assert_eq!(leaf.level, 1);
+	assert!(leaf.flags.contains(PageTableFlags::HUGE_PAGE));
+	assert!(!leaf.flags.intersects(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+}
+
+#[test_case]
+fn large_mappings_keep_huge_page_alignment(){
+	let phys = PhysAddr::new(0x20_3000);
+	let mapping = unsafe { ioremap(phys, 4 << 20, CacheMode::WriteThrough) }.unwrap();
+	assert_eq!(mapping.virt_addr().as_u64() % (2 << 20), phys.as_u64() % (2 << 20));
+	let direct: *const u64 = memory::phys_to_virt(phys).as_ptr();
+	assert_eq!(mapping.read::<u64>(0), unsafe { direct.read_volatile() });
+}
@@ -0,0 +1,118 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+	self, buddy::BuddyFrameAllocator, protect, walk,
+	frames::{self, FrameFlags},
+	vma::{VmaKind, KERNEL_VMAS},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let mapper = unsafe {memory::init(phys_mem_offset)};
+	let frame_allocator = unsafe {
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+	};
+	memory::install(mapper, frame_allocator);
+
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+// Reserves and maps three writable, non-executable pages
+fn map_area() -> VirtAddr {
+	let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | protect::no_execute();
+	let mut vmas = KERNEL_VMAS.lock();
+	let start = vmas.reserve(3 * 4096, 4096, VmaKind::Reserved, flags).unwrap();
+	memory::with_kernel_memory(|kernel| {
+		vmas.map(start, &mut kernel.mapper, &mut kernel.frame_allocator)
+	}).unwrap().unwrap();
+	start
+}
+
+#[test_case]
+fn translate_reports_every_level(){
+	let start = map_area();
+	let addr = start + 4096u64 + 0x123u64;
+	let translation = memory::translate(addr);
+
+	let frame = memory::with_kernel_memory(|kernel| {
+		kernel.mapper.translate_page(Page::containing_address(addr))
+	}).unwrap().unwrap();
+	assert_eq!(translation.phys, Some(frame.start_address() + 0x123u64));
+	assert_eq!(translation.page_size, Some(4096));
+	assert!(translation.entries.iter().all(|e| e.is_some()));
+	let leaf = translation.entries[3].unwrap();
+	assert_eq!(leaf.level, 1);
+	assert!(leaf.flags.contains(PageTableFlags::WRITABLE));
+
+	// reserved address space isn't mapped until something maps it
+	let unmapped = KERNEL_VMAS.lock()
+		.reserve(4096, 4096, VmaKind::Reserved, PageTableFlags::PRESENT)
+		.unwrap();
+	assert_eq!(memory::translate(unmapped).phys, None);
+}
+
+#[test_case]
+fn mappings_are_coalesced(){
+	let start = map_area();
+	let mut found = None;
+	walk::for_each_mapping(|range| {
+		if range.start <= start && range.end() > start.as_u64() {
+			found = Some(*range);
+		}
+	});
+	let range = found.expect("area not found");
+	assert!(range.end() >= start.as_u64() + 3 * 4096);
+	assert_eq!(range.page_size, 4096);
+	assert!(range.flags.contains(PageTableFlags::WRITABLE | protect::no_execute()));
+	assert!(!range.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+}
+
+#[test_case]
+fn new_tables_are_tagged(){
+	let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+	let mut vmas = KERNEL_VMAS.lock();
+	// a gigabyte of its own, so that mapping the page needs a new P2 and P1 table
+	let start = vmas.reserve(4096, 1 << 30, VmaKind::Reserved, flags).unwrap();
+	memory::with_kernel_memory(|kernel| {
+		vmas.map(start, &mut kernel.mapper, &mut kernel.frame_allocator)
+	}).unwrap().unwrap();
+
+	// the P3 and P2 entries point to the new tables
+	let entries = memory::translate(start).entries;
+	let tables = [entries[1].unwrap().addr, entries[2].unwrap().addr];
+	for &table in tables.iter() {
+		let descriptor = frames::descriptor(PhysFrame::containing_address(table)).unwrap();
+		assert!(descriptor.flags().contains(FrameFlags::PAGE_TABLE));
+	}
+
+	memory::with_kernel_memory(|kernel| unsafe {
+		vmas.unmap(start, &mut kernel.mapper, &mut kernel.frame_allocator)
+	}).unwrap().unwrap();
+	for &table in tables.iter() {
+		let descriptor = frames::descriptor(PhysFrame::containing_address(table)).unwrap();
+		assert!(descriptor.is_free());
+		assert!(!descriptor.flags().contains(FrameFlags::PAGE_TABLE));
+	}
+}
+
+#[test_case]
+fn dump_runs(){
+	memory::dump_page_tables();
+}
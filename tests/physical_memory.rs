@@ -0,0 +1,118 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+	self, buddy::BuddyFrameAllocator, frames::{self, FrameDescriptor, FrameFlags},
+	physmap::{self, MemoryReport, ReserveError},
+};
+use bootloader::{bootinfo::MemoryRegionType, entry_point, BootInfo};
+use core::mem::size_of;
+use core::panic::PanicInfo;
+use spin::Once;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+static BOOT_INFO: Once<&'static BootInfo> = Once::new();
+// Reserved before the frame allocator exists, where its frame database would go otherwise
+static EARLY_RESERVATION: Once<PhysAddr> = Once::new();
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	BOOT_INFO.call_once(|| boot_info);
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let mapper = unsafe {memory::init(phys_mem_offset)};
+	physmap::init(&boot_info.memory_map);
+	let early = boot_info.memory_map.iter()
+		.find(|r| r.region_type == MemoryRegionType::Usable && r.range.end_addr() - r.range.start_addr() >= 2 << 20)
+		.map(|r| PhysAddr::new(r.range.start_addr()))
+		.unwrap();
+	physmap::reserve(early, 1 << 20, "early test reservation").unwrap();
+	EARLY_RESERVATION.call_once(|| early);
+	let frame_allocator = unsafe {
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+	};
+	memory::install(mapper, frame_allocator);
+
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+	memory::with_kernel_memory(|kernel| kernel.frame_allocator.free_frames()).unwrap()
+}
+
+#[test_case]
+fn report_adds_up(){
+	let report = MemoryReport::new(&BOOT_INFO.wait().unwrap().memory_map);
+	assert!(report.usable > 0);
+	assert!(report.kernel > 0);
+	assert_eq!(
+		report.total,
+		report.usable + report.kernel + report.bootloader + report.acpi_reclaimable
+			+ report.acpi_nvs + report.reserved
+	);
+	physmap::print_report(&BOOT_INFO.wait().unwrap().memory_map);
+}
+
+#[test_case]
+fn vga_buffer_is_reserved(){
+	let reservation = physmap::reservation(PhysAddr::new(0xb8000)).unwrap();
+	assert_eq!(reservation.name, "VGA text buffer");
+	assert!(physmap::region_type(PhysAddr::new(0x10_0000)).is_some());
+}
+
+#[test_case]
+fn reserved_frames_are_never_handed_out(){
+	let free = free_frames();
+	let dma = physmap::reserve_below(16 << 20, 64 * 1024, 64 * 1024, "ISA DMA").unwrap();
+	assert!(dma.as_u64() + 64 * 1024 <= 16 << 20);
+	assert_eq!(dma.as_u64() % (64 * 1024), 0);
+	assert_eq!(free_frames(), free - 16);
+
+	let frame = PhysFrame::containing_address(dma);
+	let descriptor = frames::descriptor(frame).unwrap();
+	assert!(descriptor.flags().contains(FrameFlags::PINNED));
+	// the allocator has nothing left to give from the range
+	memory::with_kernel_memory(|kernel| {
+		let mut taken = [None; 64];
+		for slot in taken.iter_mut() {
+			*slot = kernel.frame_allocator.allocate_frame();
+			let addr = slot.unwrap().start_address().as_u64();
+			assert!(addr < dma.as_u64() || addr >= dma.as_u64() + 64 * 1024);
+		}
+		for frame in taken.iter().flatten() {
+			unsafe { kernel.frame_allocator.deallocate_frame(*frame) };
+		}
+	});
+}
+
+#[test_case]
+fn frames_in_use_cannot_be_reserved(){
+	let frame = memory::with_kernel_memory(|kernel| kernel.frame_allocator.allocate_frame()).unwrap().unwrap();
+	let result = physmap::reserve(frame.start_address(), 4096, "test");
+	assert!(matches!(result, Err(ReserveError::InUse(addr)) if addr == frame.start_address()));
+	assert!(physmap::reservation(frame.start_address()).is_none());
+	memory::with_kernel_memory(|kernel| unsafe { kernel.frame_allocator.deallocate_frame(frame) });
+}
+
+#[test_case]
+fn frame_database_avoids_reservations(){
+	let early = *EARLY_RESERVATION.wait().unwrap();
+	let offset = BOOT_INFO.wait().unwrap().physical_memory_offset;
+	let first = frames::descriptor(PhysFrame::containing_address(PhysAddr::new(0))).unwrap();
+	let table = first as *const FrameDescriptor as u64 - offset;
+	let table_end = table + (frames::frames().count() * size_of::<FrameDescriptor>()) as u64;
+	assert!(table_end <= early.as_u64() || table >= early.as_u64() + (1 << 20));
+	let descriptor = frames::descriptor(PhysFrame::containing_address(early)).unwrap();
+	assert!(descriptor.flags().contains(FrameFlags::PINNED));
+}
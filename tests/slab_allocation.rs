@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os::allocator::{slab::SlabCache, Locked};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	use blog_os::allocator;
+	use blog_os::memory::{self, buddy::BuddyFrameAllocator};
+	use x86_64::VirtAddr;
+
+	blog_os::init();
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let mut mapper = unsafe {memory::init(phys_mem_offset)};
+	let mut frame_allocator = unsafe {
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+	};
+	allocator::init_heap(&mut mapper, &mut frame_allocator)
+		.expect("heap initialization failed");
+	memory::install(mapper, frame_allocator);
+
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+struct Object {
+	value: u64,
+	padding: [u64; 7],
+}
+
+fn new_object() -> Object {
+	Object { value: 42, padding: [0; 7] }
+}
+
+lazy_static! {
+	static ref OBJECTS: Locked<SlabCache<Object>> = Locked::new(SlabCache::new(new_object));
+}
+
+#[test_case]
+fn objects_are_constructed(){
+	let mut object = OBJECTS.alloc().unwrap();
+	assert_eq!(object.value, 42);
+	assert_eq!(object.padding, [0; 7]);
+	object.value = 13;
+	assert_eq!(object.value, 13);
+}
+
+#[test_case]
+fn slabs_grow_and_get_reclaimed(){
+	// more objects than fit into a single 4 KiB slab
+	let objects: Vec<_> = (0..200).map(|_| OBJECTS.alloc().unwrap()).collect();
+	assert!(OBJECTS.lock().slab_count() > 1);
+
+	let mut addresses: Vec<_> = objects.iter().map(|o| &**o as *const Object as usize).collect();
+	addresses.sort();
+	addresses.dedup();
+	assert_eq!(addresses.len(), 200);
+
+	drop(objects);
+	OBJECTS.lock().reclaim();
+	assert_eq!(OBJECTS.lock().slab_count(), 0);
+}
@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+#![feature(asm)]
+
+use blog_os::gdt::{self, DOUBLE_FAULT_IST_INDEX};
+use blog_os::memory::{self, buddy::BuddyFrameAllocator, stack};
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
+
+// Guard page of the stack that overflows
+static mut GUARD_PAGE: u64 = 0;
+// Bounds of the guarded double fault stack set up by `gdt::init_ist_stacks`
+static mut IST_STACK: (u64, u64) = (0, 0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_guard_page::overflow_hits_guard_page...\t");
+
+    gdt::init();
+    init_test_idt();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::install(mapper, frame_allocator);
+
+    let ist_stack = gdt::init_ist_stacks();
+    let stack = stack::alloc_stack(2).expect("stack allocation failed");
+    unsafe {
+        IST_STACK = (ist_stack.bottom().as_u64(), ist_stack.top().as_u64());
+        GUARD_PAGE = stack.guard_page().start_address().as_u64();
+
+        // the overflow hits the guard page, and the page fault can't be delivered on the
+        // overflowed stack either, so the CPU raises a double fault on the IST stack
+        asm!(
+            "mov rsp, {}",
+            "call {}",
+            in(reg) stack.top().as_u64(),
+            sym overflow_stack,
+            options(noreturn)
+        );
+    }
+}
+
+extern "C" fn overflow_stack() -> ! {
+    stack_overflow();
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow(); // for each recursion, the return address is pushed
+    volatile::Volatile::new(0).read(); // prevent tail recursion optimizations
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    let (guard_page, (ist_bottom, ist_top)) = unsafe { (GUARD_PAGE, IST_STACK) };
+    let addr = Cr2::read().as_u64();
+    let stack_pointer = &addr as *const u64 as u64;
+    if addr < guard_page || addr >= guard_page + 4096 {
+        serial_println!("[failed]\nunexpected fault at {:#x}", addr);
+        exit_qemu(QemuExitCode::Failed);
+    } else if stack_pointer < ist_bottom || stack_pointer >= ist_top {
+        serial_println!(
+            "[failed]\nhandler runs on {:#x}, not on the IST stack {:#x}..{:#x}",
+            stack_pointer,
+            ist_bottom,
+            ist_top
+        );
+        exit_qemu(QemuExitCode::Failed);
+    } else {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    loop {}
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+	self, buddy::BuddyFrameAllocator, cow, frames, stack,
+	vma::{VmaKind, KERNEL_VMAS},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let mapper = unsafe {memory::init(phys_mem_offset)};
+	let frame_allocator = unsafe {
+		BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+	};
+	memory::install(mapper, frame_allocator);
+
+	test_main();
+	loop{}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+	memory::with_kernel_memory(|kernel| kernel.frame_allocator.free_frames()).unwrap()
+}
+
+// Reserves and maps an area of `size` bytes aligned to `align`
+fn map_area(size: u64, align: u64) -> VirtAddr {
+	let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+	let mut vmas = KERNEL_VMAS.lock();
+	let start = vmas.reserve(size, align, VmaKind::Reserved, flags).unwrap();
+	memory::with_kernel_memory(|kernel| {
+		vmas.map(start, &mut kernel.mapper, &mut kernel.frame_allocator)
+	}).unwrap().unwrap();
+	start
+}
+
+#[test_case]
+fn unmapping_returns_frames_and_tables(){
+	let free = free_frames();
+	// a fresh 1 GiB aligned range needs its own P1 and P2 tables
+	let start = map_area(2 << 20, 1 << 30);
+	let pages = Page::range_inclusive(
+		Page::containing_address(start),
+		Page::containing_address(start + ((2u64 << 20) - 1)),
+	);
+
+	let stats = unsafe { memory::unmap_kernel_range(pages) }.unwrap().unwrap();
+	assert_eq!(stats.pages, 512);
+	assert_eq!(stats.frames_freed, 512);
+	assert!(stats.tables_freed >= 2);
+	assert_eq!(free_frames(), free);
+
+	let translated = memory::with_kernel_memory(|kernel| kernel.mapper.translate_page(pages.start)).unwrap();
+	assert!(translated.is_err());
+	KERNEL_VMAS.lock().release(start).unwrap();
+}
+
+#[test_case]
+fn shared_frames_stay_mapped(){
+	let src = Page::containing_address(map_area(4096, 4096));
+	let dst_start = KERNEL_VMAS.lock()
+		.reserve(4096, 4096, VmaKind::Reserved, PageTableFlags::PRESENT)
+		.unwrap();
+	let dst = Page::containing_address(dst_start);
+	unsafe { src.start_address().as_mut_ptr::<u64>().write_volatile(5) };
+	cow::share(src, dst).unwrap();
+
+	let stats = unsafe { memory::unmap_kernel_range(Page::range_inclusive(dst, dst)) }.unwrap().unwrap();
+	assert_eq!(stats.pages, 1);
+	assert_eq!(stats.frames_freed, 0);
+
+	let frame = memory::with_kernel_memory(|kernel| kernel.mapper.translate_page(src)).unwrap().unwrap();
+	assert_eq!(frames::refcount(frame), 1);
+	unsafe { assert_eq!(src.start_address().as_ptr::<u64>().read_volatile(), 5) };
+}
+
+#[test_case]
+fn freed_stacks_give_everything_back(){
+	let free = free_frames();
+	let stack = stack::alloc_stack(4).unwrap();
+	assert!(free_frames() <= free - 4);
+
+	let bottom = stack.bottom();
+	unsafe { stack::free_stack(stack) };
+	assert_eq!(free_frames(), free);
+	assert!(KERNEL_VMAS.lock().find(bottom).is_none());
+	assert_eq!(memory::translate(bottom).phys, None);
+}